@@ -0,0 +1,136 @@
+//! End-to-end deposit flow against a local Anvil and the running service:
+//! create a session, deposit the token to the customer address, and print the webhooks.
+//!
+//! Setup:
+//! 1. Start Anvil: `anvil --block-time 2`
+//! 2. Deploy an ERC-20 with 6 decimals to Anvil, minted to the first Anvil account, e.g.
+//!    `forge create --rpc-url http://127.0.0.1:8545 --private-key 0xac09...ff80 MockUSDC`
+//! 3. Configure the chain in `config.toml`:
+//!    ```toml
+//!    [[chains]]
+//!    chain_type="evm"
+//!    chain_name="anvil"
+//!    latency=1
+//!    estimation=2
+//!    commission_bps=0
+//!    commission_min=0
+//!    commission_max=0
+//!    rpc="http://127.0.0.1:8545"
+//!    tokens=["USDC:0xYourToken"]
+//!    ```
+//! 4. Start the service with the webhook of this example:
+//!    `WEBHOOK=http://127.0.0.1:9100/webhook APIKEY=test cargo run -p api`
+//! 5. Run this example:
+//!    `APIKEY=test TOKEN=0xYourToken cargo run -p api --example deposit_flow`
+//!
+//! Optional envs: `ZEROPAY_URL` (default http://127.0.0.1:9000), `RPC` (default Anvil),
+//! `PAYER` (default the first Anvil account key), `DECIMALS` (default 6),
+//! `WEBHOOK_PORT` (default 9100), `AMOUNT` in cents (default 1000).
+
+use alloy::{
+    network::EthereumWallet,
+    primitives::{Address, U256},
+    providers::ProviderBuilder,
+    signers::local::PrivateKeySigner,
+    sol,
+};
+use axum::{Router, body::Bytes, extract::State, http::HeaderMap, routing::post};
+use serde_json::{Value, json};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{UnboundedSender, unbounded_channel},
+};
+
+/// The first Anvil default account
+const ANVIL_KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+sol! {
+    #[sol(rpc)]
+    interface Erc20 {
+        function transfer(address to, uint256 amount) external returns (bool);
+    }
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or(default.to_owned())
+}
+
+// print the received webhook, and forward the event to the flow
+async fn webhook(State(sender): State<UnboundedSender<Value>>, headers: HeaderMap, body: Bytes) {
+    let event: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let hmac = headers
+        .get("X-HMAC")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    println!("<- webhook (X-HMAC: {hmac}): {event}");
+    let _ = sender.send(event);
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let url = env_or("ZEROPAY_URL", "http://127.0.0.1:9000");
+    let apikey = std::env::var("APIKEY")?;
+    let rpc = env_or("RPC", "http://127.0.0.1:8545");
+    let payer: PrivateKeySigner = env_or("PAYER", ANVIL_KEY).parse()?;
+    let token: Address = std::env::var("TOKEN")?.parse()?;
+    let decimals: u8 = env_or("DECIMALS", "6").parse()?;
+    let port: u16 = env_or("WEBHOOK_PORT", "9100").parse()?;
+    let amount: i32 = env_or("AMOUNT", "1000").parse()?;
+
+    // 1. listen the webhooks
+    let (sender, mut events) = unbounded_channel();
+    let router = Router::new()
+        .route("/webhook", post(webhook))
+        .with_state(sender);
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))).await?;
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    println!("webhook listening on 127.0.0.1:{port}/webhook");
+
+    // 2. create the payment session
+    let customer = format!("example_{}", std::process::id());
+    let session: Value = reqwest::Client::new()
+        .post(format!("{url}/sessions?apikey={apikey}"))
+        .json(&json!({ "customer": customer, "amount": amount }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    println!("-> session: {session}");
+    let pay_eth: Address = session["pay_eth"]
+        .as_str()
+        .ok_or(anyhow::anyhow!("No pay_eth in session"))?
+        .parse()?;
+
+    // 3. the customer deposits the token, amount is in cents
+    let value = U256::from(amount) * U256::from(10).pow(U256::from(decimals - 2));
+    let provider = ProviderBuilder::new()
+        .wallet(EthereumWallet::from(payer))
+        .connect_http(rpc.parse()?);
+    let receipt = Erc20::new(token, provider)
+        .transfer(pay_eth, value)
+        .send()
+        .await?
+        .get_receipt()
+        .await?;
+    println!(
+        "-> deposited {value} to {pay_eth}: {:?}",
+        receipt.transaction_hash
+    );
+
+    // 4. wait the paid and settled webhooks
+    let wait = async {
+        while let Some(event) = events.recv().await {
+            if event["event"] == "session.settled" {
+                break;
+            }
+        }
+    };
+    match tokio::time::timeout(Duration::from_secs(300), wait).await {
+        Ok(()) => println!("deposit flow completed"),
+        Err(_) => println!("no settled webhook in 5 minutes, check the service logs"),
+    }
+
+    Ok(())
+}
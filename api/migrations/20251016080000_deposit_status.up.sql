@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE deposits ADD COLUMN IF NOT EXISTS status VARCHAR NOT NULL DEFAULT 'detected';
+UPDATE deposits SET status='settled' WHERE settled_tx IS NOT NULL;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS resources (
+  resource      VARCHAR PRIMARY KEY,
+  resource_type VARCHAR NOT NULL,
+  x402_version  INT NOT NULL,
+  accepts       VARCHAR NOT NULL,
+  metadata      VARCHAR,
+  last_updated  TIMESTAMP NOT NULL
+)
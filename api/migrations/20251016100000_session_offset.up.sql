@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS amount_offset INT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE deposits ADD COLUMN IF NOT EXISTS asset VARCHAR NOT NULL DEFAULT '';
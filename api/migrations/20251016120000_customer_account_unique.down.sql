@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS customers_account_key;
+ALTER TABLE customers DROP COLUMN alias_of
@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- keep the duplicate accounts as aliases of the lowest id, their addresses, sessions and
+-- deposits stay with them, so the addresses are still scanned, released and exported
+ALTER TABLE customers ADD COLUMN IF NOT EXISTS alias_of INT;
+UPDATE customers c SET alias_of=k.id
+FROM (SELECT account, MIN(id) AS id FROM customers GROUP BY account HAVING COUNT(*) > 1) k
+WHERE c.account=k.account AND c.id<>k.id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS customers_account_key ON customers (account) WHERE alias_of IS NULL;
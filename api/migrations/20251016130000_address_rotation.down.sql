@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS retired_addresses;
+ALTER TABLE deposits DROP COLUMN address_index;
+ALTER TABLE customers DROP COLUMN address_index
@@ -0,0 +1,9 @@
+-- Add up migration script here
+ALTER TABLE customers ADD COLUMN IF NOT EXISTS address_index INT NOT NULL DEFAULT 0;
+ALTER TABLE deposits ADD COLUMN IF NOT EXISTS address_index INT NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS retired_addresses (
+  eth           VARCHAR PRIMARY KEY,
+  customer      INT NOT NULL,
+  address_index INT NOT NULL,
+  retired_at    TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP SEQUENCE IF EXISTS webhook_sequence
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add up migration script here
+CREATE SEQUENCE IF NOT EXISTS webhook_sequence;
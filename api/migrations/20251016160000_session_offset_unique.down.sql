@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS sessions_offset_reserved_key;
+ALTER TABLE sessions DROP COLUMN offset_reserved
\ No newline at end of file
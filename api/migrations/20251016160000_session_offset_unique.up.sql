@@ -0,0 +1,11 @@
+-- Add up migration script here
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS offset_reserved BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- reserve the offsets of the unpaid sessions which may still match, the newest one of the same amount
+UPDATE sessions SET offset_reserved=TRUE WHERE id IN (
+    SELECT DISTINCT ON (customer, amount) id FROM sessions
+    WHERE amount_offset>0 AND deposit IS NULL AND expired_at>now() - interval '1 day'
+    ORDER BY customer, amount, id DESC
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS sessions_offset_reserved_key ON sessions(customer, amount) WHERE offset_reserved;
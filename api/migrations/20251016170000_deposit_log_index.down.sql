@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS deposits_tx_log_key;
+ALTER TABLE deposits DROP COLUMN log_index
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- the log index of the token transfer, NULL is the whole transaction (native or legacy)
+ALTER TABLE deposits ADD COLUMN IF NOT EXISTS log_index BIGINT;
+
+-- the legacy duplicates of a transaction keep their rows, with a negative log index by id
+UPDATE deposits SET log_index=-id WHERE log_index IS NULL AND id NOT IN (SELECT MIN(id) FROM deposits GROUP BY tx);
+
+CREATE UNIQUE INDEX IF NOT EXISTS deposits_tx_log_key ON deposits(tx, COALESCE(log_index, -1));
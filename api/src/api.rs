@@ -1,13 +1,24 @@
 use crate::AppState;
 use crate::error::{ApiError, Result};
-use crate::models::{Customer, Session, store_address_in_redis};
+use crate::models::{
+    ADDRESS_TTL, Customer, Deposit, RetiredAddress, Session, Storage, count_session_in_redis,
+    store_address_in_redis, store_verified_in_redis, take_verified_in_redis,
+};
 use axum::extract::{Json, Path, Query, State};
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use scanner::generate_eth;
+use scanner::{
+    ChainConfig, MAX_BLOCKS_PER_SCAN, SCAN_INTERVAL_CATCHUP, SCAN_INTERVAL_ERROR,
+    SCAN_INTERVAL_FATAL, SCAN_INTERVAL_NORMAL, SCAN_INTERVAL_RETRY, ScannerConfig, ScannerMessage,
+    ScannerStorage, SettlementStatus,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use std::sync::Arc;
 use x402::{
-    DiscoveryRequest, DiscoveryResponse, Payee, PaymentRequirementsResponse, SettlementResponse,
-    SupportedResponse, VerifyRequest,
+    DiscoveryItem, DiscoveryRequest, DiscoveryResponse, Payee, PaymentRequirements,
+    PaymentRequirementsResponse, RequirementsFilter, SettlementResponse, SupportedResponse,
+    VerifyRequest, VerifyResponse, X402_VERSION,
 };
 
 #[derive(Deserialize)]
@@ -15,10 +26,22 @@ pub struct ApikeyAuth {
     apikey: String,
 }
 
+impl ApikeyAuth {
+    /// admin endpoints are disabled when no admin key configured
+    fn check_admin(&self, app: &AppState) -> Result<()> {
+        match &app.adminkey {
+            Some(adminkey) if &self.apikey == adminkey => Ok(()),
+            _ => Err(ApiError::UserAuth),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CreateSession {
     customer: String,
     amount: i32,
+    /// add a unique amount offset, so the deposit is matched exactly
+    unique: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -27,21 +50,42 @@ pub struct SessionResponse {
     customer: String,
     pay_eth: String,
     amount: i32,
+    amount_offset: i32,
     expired: NaiveDateTime,
     completed: bool,
+    status: Option<SettlementStatus>,
 }
 
 impl SessionResponse {
-    fn new(customer: Customer, session: Session) -> SessionResponse {
-        SessionResponse {
+    fn new(
+        customer: Customer,
+        session: Session,
+        deposit: Option<Deposit>,
+    ) -> Result<SessionResponse> {
+        Ok(SessionResponse {
             session_id: session.id,
             customer: customer.account,
             pay_eth: customer.eth,
             amount: session.amount,
+            amount_offset: session.amount_offset,
             expired: session.expired_at,
             completed: session.deposit.is_some(),
+            status: deposit.map(|d| d.settlement_status()).transpose()?,
+        })
+    }
+}
+
+/// The endpoints which provision customers and addresses share the per-minute session limit
+async fn check_session_rate(app: &AppState) -> Result<()> {
+    if app.session_rate_limit > 0 {
+        let count = count_session_in_redis(&app.redis)
+            .await
+            .map_err(|_| ApiError::Internal)?;
+        if count > app.session_rate_limit {
+            return Err(ApiError::RateLimited);
         }
     }
+    Ok(())
 }
 
 pub async fn create_session(
@@ -53,15 +97,27 @@ pub async fn create_session(
         return Err(ApiError::UserAuth);
     }
 
-    let customer = Customer::get_or_insert(data.customer, &app.db, &app.mnemonics).await?;
-    let session = Session::insert(customer.id, data.amount, &app.db).await?;
+    check_session_rate(&app).await?;
+
+    let customer = Customer::get_or_insert(data.customer, &app.db, app.signer.as_ref()).await?;
+    let session = if data.unique.unwrap_or(false) {
+        Session::insert_unique(customer.id, data.amount, app.session_grace, &app.db).await?
+    } else {
+        Session::insert(customer.id, data.amount, &app.db).await?
+    };
 
     // save address to redis cache
-    store_address_in_redis(&app.redis, &customer.eth, customer.id)
-        .await
-        .map_err(|_| ApiError::Internal)?;
+    store_address_in_redis(
+        &app.redis,
+        &customer.eth,
+        customer.id,
+        customer.address_index,
+        ADDRESS_TTL,
+    )
+    .await
+    .map_err(|_| ApiError::Internal)?;
 
-    Ok(Json(SessionResponse::new(customer, session)))
+    Ok(Json(SessionResponse::new(customer, session, None)?))
 }
 
 pub async fn get_session(
@@ -75,19 +131,329 @@ pub async fn get_session(
 
     let session = Session::get(id, &app.db).await?;
     let customer = Customer::get(session.customer, &app.db).await?;
+    let deposit = match session.deposit {
+        Some(did) => Deposit::get(did, &app.db).await.ok(),
+        None => None,
+    };
+
+    Ok(Json(SessionResponse::new(customer, session, deposit)?))
+}
+
+/// Release the held deposit, settle it to merchant wallet
+pub async fn release_deposit(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Path(id): Path<i32>,
+) -> Result<Json<Value>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+
+    let deposit = Deposit::claim_held(id, &app.db)
+        .await?
+        .ok_or(ApiError::Verify("deposit is not held".to_owned()))?;
+    let did = deposit.id;
+    if let Err(err) = send_release(&app, deposit).await {
+        // not released, hold it again
+        Deposit::set_status(did, SettlementStatus::Held, &app.db).await?;
+        return Err(err);
+    }
+
+    Ok(Json(json!({ "status": "success" })))
+}
 
-    Ok(Json(SessionResponse::new(customer, session)))
+// send the claimed deposit to the scanner to settle
+async fn send_release(app: &AppState, deposit: Deposit) -> Result<()> {
+    let customer = Customer::get(deposit.customer, &app.db).await?;
+
+    // the deposit address maybe retired, cache it again so the scanner can derive its key
+    let eth = app
+        .signer
+        .address(deposit.address_index, customer.id)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    let ttl = if deposit.address_index == customer.address_index {
+        ADDRESS_TTL
+    } else {
+        app.address_grace.max(1)
+    };
+    store_address_in_redis(&app.redis, &eth, customer.id, deposit.address_index, ttl)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    app.sender
+        .send(ScannerMessage::Release(deposit.id, deposit.asset, eth))
+        .map_err(|_| ApiError::Internal)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct RotateResponse {
+    customer: String,
+    pay_eth: String,
+    address_index: i32,
+    retired_eth: String,
+    retired_until: NaiveDateTime,
+}
+
+/// Rotate the customer deposit address, the new sessions use the next derived address,
+/// and the retired one is still scanned in the grace period, so the late deposits and
+/// the in-flight sessions are still resolved
+pub async fn rotate_customer_address(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Path(account): Path<String>,
+) -> Result<Json<RotateResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+
+    let (customer, retired) = Customer::rotate(&account, &app.db, app.signer.as_ref()).await?;
+    let retired_until = retired.retired_at + TimeDelta::seconds(app.address_grace as i64);
+
+    store_address_in_redis(
+        &app.redis,
+        &customer.eth,
+        customer.id,
+        customer.address_index,
+        ADDRESS_TTL,
+    )
+    .await
+    .map_err(|_| ApiError::Internal)?;
+    store_address_in_redis(
+        &app.redis,
+        &retired.eth,
+        customer.id,
+        retired.address_index,
+        app.address_grace.max(1),
+    )
+    .await
+    .map_err(|_| ApiError::Internal)?;
+    info!(
+        "Rotated customer {} address {} to {}",
+        customer.id, retired.eth, customer.eth
+    );
+
+    Ok(Json(RotateResponse {
+        customer: customer.account,
+        pay_eth: customer.eth,
+        address_index: customer.address_index,
+        retired_eth: retired.eth,
+        retired_until,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SandboxDeposit {
+    customer: String,
+    amount: i32,
+}
+
+/// Simulate a deposit to the customer and its settlement in sandbox mode, no onchain transaction,
+/// it runs the same flow as the scanner, so the sessions and webhooks are real, marked with sandbox
+pub async fn sandbox_deposit(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<SandboxDeposit>,
+) -> Result<Json<Value>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+    if !app.sandbox {
+        return Err(ApiError::Verify("sandbox is disabled".to_owned()));
+    }
+    if data.amount <= 0 {
+        return Err(ApiError::Verify("invalid amount".to_owned()));
+    }
+
+    let customer = Customer::get_by_account(&data.customer, &app.db).await?;
+    let storage = Storage {
+        db: app.db.clone(),
+        redis: app.redis.clone(),
+        apikey: app.apikey.clone(),
+        webhook: app.webhook.clone(),
+        wallet: app.wallet.clone(),
+        sandbox: true,
+        hold_unmatched: false,
+        session_grace: app.session_grace,
+    };
+
+    // 1. deposited, match the session and send paid event
+    let tx = sandbox_tx();
+    let did = storage
+        .deposited(
+            "sandbox".to_owned(),
+            customer.address_index,
+            customer.id,
+            data.amount,
+            tx.clone(),
+            None,
+        )
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    // 2. settled with the commission of the first chain
+    let fee = app
+        .chains
+        .first()
+        .map(|c| c.commission_of(data.amount))
+        .unwrap_or_default();
+    let settled_amount = (data.amount - fee).max(0);
+    let settled_tx = sandbox_tx();
+    storage
+        .settled(
+            "sandbox".to_owned(),
+            did,
+            settled_amount,
+            settled_tx.clone(),
+        )
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(Json(json!({
+        "deposit": did,
+        "tx": tx,
+        "settled_amount": settled_amount,
+        "settled_tx": settled_tx,
+        "sandbox": true,
+    })))
+}
+
+/// Max deposits replayed by one request, replay the rest after the last deposit id,
+/// it bounds the request to about 20 seconds
+const MAX_REPLAY_DEPOSITS: i64 = 100;
+
+/// Interval between the replayed webhooks, not overwhelm the merchant endpoint
+const REPLAY_INTERVAL_MS: u64 = 100;
+
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    /// unix timestamp, replay the deposits created from it
+    from: i64,
+    /// unix timestamp, replay the deposits created before it, default is now
+    to: Option<i64>,
+    /// replay the deposits after this id (exclusive), the `last_id` of the previous page
+    after_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct ReplayFailure {
+    deposit: i32,
+    event: String,
+}
+
+#[derive(Serialize)]
+pub struct ReplayResponse {
+    deposits: usize,
+    replayed: usize,
+    failures: Vec<ReplayFailure>,
+    /// the id of the last replayed deposit, replay the next page after it when reach the max
+    last_id: Option<i32>,
+}
+
+/// Send the webhooks of the deposits in the time window again, in the order they happened,
+/// so the merchant can rebuild the state after its endpoint was down
+pub async fn replay_webhooks(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Query(query): Query<ReplayQuery>,
+) -> Result<Json<ReplayResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+    let Some(webhook) = &app.webhook else {
+        return Err(ApiError::Verify("webhook is not configured".to_owned()));
+    };
+
+    let from = DateTime::from_timestamp(query.from, 0)
+        .ok_or(ApiError::Verify("invalid from".to_owned()))?
+        .naive_utc();
+    let to = match query.to {
+        Some(to) => DateTime::from_timestamp(to, 0)
+            .ok_or(ApiError::Verify("invalid to".to_owned()))?
+            .naive_utc(),
+        None => Utc::now().naive_utc(),
+    };
+    if from >= to {
+        return Err(ApiError::Verify("from must be before to".to_owned()));
+    }
+
+    let storage = Storage {
+        db: app.db.clone(),
+        redis: app.redis.clone(),
+        apikey: app.apikey.clone(),
+        webhook: app.webhook.clone(),
+        wallet: app.wallet.clone(),
+        sandbox: false,
+        hold_unmatched: false,
+        session_grace: app.session_grace,
+    };
+    let after_id = query.after_id.unwrap_or(0);
+    let deposits = Deposit::list_between(from, to, after_id, MAX_REPLAY_DEPOSITS, &app.db).await?;
+    let mut replayed = 0;
+    let mut failures = vec![];
+    for deposit in deposits.iter() {
+        let events = storage
+            .replay_events(deposit)
+            .await
+            .map_err(|_| ApiError::Internal)?;
+        for event in events {
+            let name = event.name().to_owned();
+            let sandbox = deposit.asset == "sandbox";
+            match storage.send_event_as(webhook, event, sandbox).await {
+                Ok(()) => replayed += 1,
+                Err(_) => failures.push(ReplayFailure {
+                    deposit: deposit.id,
+                    event: name,
+                }),
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(REPLAY_INTERVAL_MS)).await;
+        }
+    }
+    info!(
+        "Replayed {} webhooks of {} deposits, {} failed",
+        replayed,
+        deposits.len(),
+        failures.len()
+    );
+
+    Ok(Json(ReplayResponse {
+        deposits: deposits.len(),
+        replayed,
+        failures,
+        last_id: deposits.last().map(|d| d.id),
+    }))
+}
+
+// synthetic transaction hash for sandbox
+fn sandbox_tx() -> String {
+    format!(
+        "0x{:064x}",
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    )
+}
+
+#[derive(Deserialize)]
+pub struct X402Requirements {
+    customer: String,
+    amount: i32,
+    /// only the requirements of these token symbols or addresses, default all
+    tokens: Option<Vec<String>>,
+    /// only the requirements of these networks, default all
+    networks: Option<Vec<String>>,
 }
 
 pub async fn x402_requirements(
     State(app): State<Arc<AppState>>,
     Query(auth): Query<ApikeyAuth>,
-    Json(data): Json<CreateSession>,
+    Json(data): Json<X402Requirements>,
 ) -> Result<Json<PaymentRequirementsResponse>> {
     if auth.apikey != app.apikey {
         return Err(ApiError::UserAuth);
     }
-    let customer = Customer::get_or_insert(data.customer, &app.db, &app.mnemonics).await?;
+    check_session_rate(&app).await?;
+
+    let customer = Customer::get_or_insert(data.customer, &app.db, app.signer.as_ref()).await?;
 
     // convert amount (2-decimal) to f32 price
     let price = format!("{:.2}", data.amount as f32 / 10f32.powi(2));
@@ -95,7 +461,153 @@ pub async fn x402_requirements(
         evm: Some(customer.eth),
         sol: None,
     };
-    let res = app.facilitator.create(&price, payee);
+    let filter = RequirementsFilter {
+        tokens: data.tokens.unwrap_or_default(),
+        networks: data.networks.unwrap_or_default(),
+    };
+    let res = app.facilitator.create(&price, payee, Some(&filter));
+
+    Ok(Json(res))
+}
+
+#[derive(Deserialize)]
+pub struct Estimate {
+    /// the amount customer sends (2-decimal)
+    gross: i32,
+    /// token symbol, e.g. USDC
+    token: String,
+    /// chain name, e.g. ethereum
+    chain: String,
+}
+
+#[derive(Serialize)]
+pub struct EstimateResponse {
+    chain: String,
+    token: String,
+    gross: i32,
+    commission: i32,
+    gas: i32,
+    fees: i32,
+    net: i32,
+    held: bool,
+}
+
+/// Projected merchant net amount of a customer gross amount, the fees are the same as the
+/// settlement: the commission raised by the gas guard, or held if it can not cover the gas
+pub async fn estimate(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Query(data): Query<Estimate>,
+) -> Result<Json<EstimateResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+    if data.gross <= 0 {
+        return Err(ApiError::Verify("invalid gross".to_owned()));
+    }
+
+    let chain = app
+        .chains
+        .iter()
+        .find(|c| c.chain_name == data.chain)
+        .ok_or(ApiError::NotFound)?;
+    if !chain
+        .tokens
+        .iter()
+        .any(|t| t.split(':').next() == Some(data.token.as_str()))
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    let estimate = chain
+        .estimate(&data.token, data.gross)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    let net = if estimate.held {
+        0
+    } else {
+        (data.gross - estimate.fee).max(0)
+    };
+    Ok(Json(EstimateResponse {
+        chain: data.chain,
+        token: data.token,
+        gross: data.gross,
+        commission: estimate.commission,
+        gas: estimate.gas,
+        fees: estimate.fee,
+        net,
+        held: estimate.held,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct Quote {
+    /// price in USD, e.g. "0.01"
+    price: String,
+    /// receiving address, default is the merchant wallet
+    pay_to: Option<String>,
+    /// comma-separated token symbols or addresses, default all
+    tokens: Option<String>,
+    /// comma-separated networks, default all
+    networks: Option<String>,
+}
+
+// the price is a plain positive decimal, e.g. "0.01", no sign, exponent or infinity
+fn valid_price(price: &str) -> bool {
+    let (int, frac) = price.split_once('.').unwrap_or((price, "0"));
+    !int.is_empty()
+        && !frac.is_empty()
+        && int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit())
+        && price.bytes().any(|b| (b'1'..=b'9').contains(&b))
+}
+
+/// Stateless payment requirements of a price, no customer is created
+pub async fn x402_quote(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Query(data): Query<Quote>,
+) -> Result<Json<PaymentRequirementsResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+    if !valid_price(&data.price) {
+        return Err(ApiError::Verify("invalid price".to_owned()));
+    }
+
+    let pay_to = data.pay_to.unwrap_or(app.wallet.clone());
+    let valid = pay_to.len() == 42
+        && pay_to
+            .strip_prefix("0x")
+            .is_some_and(|a| hex::decode(a).is_ok());
+    if !valid {
+        return Err(ApiError::Verify("invalid pay_to".to_owned()));
+    }
+
+    let payee = Payee {
+        evm: Some(pay_to),
+        sol: None,
+    };
+    let split = |s: Option<String>| -> Vec<String> {
+        s.unwrap_or_default()
+            .split(',')
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .collect()
+    };
+    let filter = RequirementsFilter {
+        tokens: split(data.tokens),
+        networks: split(data.networks),
+    };
+    let mut res = app.facilitator.create(&data.price, payee, Some(&filter));
+
+    // a token can not be paid below its smallest unit
+    let matched = res.accepts.len();
+    res.accepts.retain(|pr| pr.max_amount_required != "0");
+    if matched > 0 && res.accepts.is_empty() {
+        return Err(ApiError::Verify(
+            "price is below the smallest token unit".to_owned(),
+        ));
+    }
 
     Ok(Json(res))
 }
@@ -119,6 +631,51 @@ pub async fn x402_payment(
     Ok(Json(res2))
 }
 
+/// Verify the payment without settling, the valid one is cached until the authorization expired,
+/// so the later settle skips the verification
+pub async fn x402_verify(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<VerifyRequest>,
+) -> Result<Json<VerifyResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+
+    let res = app.facilitator.verify(&data).await;
+    if res.is_valid && res.scheduled_at.is_none() {
+        store_verified_in_redis(&app.redis, &data)
+            .await
+            .map_err(|_| ApiError::Internal)?;
+    }
+
+    Ok(Json(res))
+}
+
+/// Settle the payment, verify it again if it is not verified or the verification expired
+pub async fn x402_settle(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<VerifyRequest>,
+) -> Result<Json<SettlementResponse>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+
+    let verified = take_verified_in_redis(&app.redis, &data)
+        .await
+        .unwrap_or(false);
+    if !verified {
+        let res = app.facilitator.verify(&data).await;
+        if !res.is_valid {
+            return Ok(Json(res.to_settle(&data.payment_payload.network, "")));
+        }
+    }
+
+    let res = app.facilitator.settle(&data).await;
+    Ok(Json(res))
+}
+
 pub async fn x402_support(
     State(app): State<Arc<AppState>>,
     Query(auth): Query<ApikeyAuth>,
@@ -140,6 +697,315 @@ pub async fn x402_discovery(
         return Err(ApiError::UserAuth);
     }
 
-    let res = app.facilitator.discovery(data);
+    let res = app.facilitator.discovery(data).await;
     Ok(Json(res))
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterResource {
+    /// the resource URL being monetized
+    resource: String,
+    /// resource type, default is "http"
+    #[serde(rename = "type")]
+    r#type: Option<String>,
+    /// the payment requirements of the resource
+    accepts: Vec<PaymentRequirements>,
+    /// additional metadata (category, provider, etc.)
+    metadata: Option<Value>,
+}
+
+pub async fn x402_resource(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<RegisterResource>,
+) -> Result<Json<Value>> {
+    if auth.apikey != app.apikey {
+        return Err(ApiError::UserAuth);
+    }
+    if data.resource.is_empty() || data.accepts.is_empty() {
+        return Err(ApiError::Verify("invalid resource".to_owned()));
+    }
+
+    let item = DiscoveryItem {
+        resource: data.resource,
+        r#type: data.r#type.unwrap_or("http".to_owned()),
+        x402_version: X402_VERSION,
+        accepts: data.accepts,
+        last_updated: Utc::now().timestamp(),
+        metadata: data.metadata,
+    };
+    app.facilitator
+        .add_resource(item)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(Json(json!({ "status": "success" })))
+}
+
+/// Prometheus metrics, by the admin key
+pub async fn metrics(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+) -> Result<String> {
+    auth.check_admin(&app)?;
+
+    Ok(app.metrics.render())
+}
+
+#[derive(Clone, Serialize)]
+pub struct ScanIntervals {
+    max_blocks_per_scan: u64,
+    catchup: u64,
+    normal: u64,
+    error: u64,
+    retry: u64,
+    fatal: u64,
+}
+
+/// The effective loaded configure, all secrets are redacted
+#[derive(Clone, Serialize)]
+pub struct ConfigResponse {
+    wallet: String,
+    webhook: Option<String>,
+    agent_id: Option<i64>,
+    agent_identity: Option<String>,
+    chains: Vec<ChainConfig>,
+    scan_intervals: ScanIntervals,
+}
+
+impl ConfigResponse {
+    pub fn new(
+        config: &ScannerConfig,
+        wallet: &str,
+        webhook: Option<&str>,
+        agent_id: Option<i64>,
+        agent_identity: Option<&str>,
+    ) -> ConfigResponse {
+        let chains = config
+            .chains
+            .iter()
+            .map(|chain| {
+                let mut chain = chain.clone();
+                chain.rpc = redact_url(&chain.rpc);
+                chain.admin = chain.admin.map(|_| REDACTED.to_owned());
+                chain
+            })
+            .collect();
+
+        ConfigResponse {
+            wallet: wallet.to_owned(),
+            webhook: webhook.map(redact_url),
+            agent_id,
+            agent_identity: agent_identity.map(|s| s.to_owned()),
+            chains,
+            scan_intervals: ScanIntervals {
+                max_blocks_per_scan: MAX_BLOCKS_PER_SCAN,
+                catchup: SCAN_INTERVAL_CATCHUP,
+                normal: SCAN_INTERVAL_NORMAL,
+                error: SCAN_INTERVAL_ERROR,
+                retry: SCAN_INTERVAL_RETRY,
+                fatal: SCAN_INTERVAL_FATAL,
+            },
+        }
+    }
+}
+
+const REDACTED: &str = "***";
+
+// keep only scheme and host, url path, query and userinfo often contain the api keys
+fn redact_url(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+
+    if host.len() < rest.trim_end_matches('/').len() {
+        format!("{scheme}://{host}/{REDACTED}")
+    } else {
+        format!("{scheme}://{host}")
+    }
+}
+
+pub async fn admin_config(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+) -> Result<Json<ConfigResponse>> {
+    auth.check_admin(&app)?;
+
+    Ok(Json(app.config.clone()))
+}
+
+#[derive(Deserialize)]
+pub struct Rescan {
+    chain: String,
+    from_block: u64,
+    to_block: u64,
+}
+
+pub async fn admin_rescan(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<Rescan>,
+) -> Result<Json<Value>> {
+    auth.check_admin(&app)?;
+
+    if data.from_block > data.to_block {
+        return Err(ApiError::Verify("invalid block range".to_owned()));
+    }
+    if data.to_block - data.from_block >= app.rescan_max_blocks {
+        return Err(ApiError::Verify(format!(
+            "block range is more than {} blocks",
+            app.rescan_max_blocks
+        )));
+    }
+    if !app.config.chains.iter().any(|c| c.chain_name == data.chain) {
+        return Err(ApiError::NotFound);
+    }
+
+    app.sender
+        .send(ScannerMessage::Rescan(
+            data.chain,
+            data.from_block,
+            data.to_block,
+        ))
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(Json(json!({ "status": "success" })))
+}
+
+pub async fn admin_pause_chain(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>> {
+    chain_control(&app, auth, ScannerMessage::Pause(name))
+}
+
+pub async fn admin_resume_chain(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>> {
+    chain_control(&app, auth, ScannerMessage::Resume(name))
+}
+
+fn chain_control(app: &AppState, auth: ApikeyAuth, msg: ScannerMessage) -> Result<Json<Value>> {
+    auth.check_admin(app)?;
+
+    let name = match &msg {
+        ScannerMessage::Pause(name) | ScannerMessage::Resume(name) => name,
+        _ => return Err(ApiError::Internal),
+    };
+    if !app.config.chains.iter().any(|c| &c.chain_name == name) {
+        return Err(ApiError::NotFound);
+    }
+
+    app.sender.send(msg).map_err(|_| ApiError::Internal)?;
+
+    Ok(Json(json!({ "status": "success" })))
+}
+
+#[derive(Deserialize)]
+pub struct ExportKeys {
+    /// age x25519 public key (age1...) to encrypt the keys
+    recipient: String,
+}
+
+#[derive(Serialize)]
+struct ExportedKey {
+    customer: String,
+    eth: String,
+    secret: String,
+}
+
+/// Export all derived customer private keys, encrypted to the given age recipient,
+/// they are never returned in plaintext. Used when merchant offboarding
+pub async fn admin_export_keys(
+    State(app): State<Arc<AppState>>,
+    Query(auth): Query<ApikeyAuth>,
+    Json(data): Json<ExportKeys>,
+) -> Result<Json<Value>> {
+    auth.check_admin(&app)?;
+    if !app.allow_key_export {
+        warn!("AUDIT: rejected customer keys export, export is disabled");
+        return Err(ApiError::UserAuth);
+    }
+    let Some(mnemonics) = &app.mnemonics else {
+        return Err(ApiError::Verify(
+            "customer keys are managed by the external signer".to_owned(),
+        ));
+    };
+
+    let recipient: age::x25519::Recipient = data
+        .recipient
+        .parse()
+        .map_err(|_| ApiError::Verify("invalid age recipient".to_owned()))?;
+
+    let customers = Customer::list_all(&app.db).await?;
+    let retired = RetiredAddress::list_all(&app.db).await?;
+    let mut keys = vec![];
+    for customer in customers {
+        if customer.eth.is_empty() {
+            continue;
+        }
+        let (secret, eth) = generate_eth(customer.address_index, customer.id, mnemonics)
+            .map_err(|_| ApiError::Internal)?;
+        if eth != customer.eth {
+            error!("Derived address mismatch of customer: {}", customer.id);
+            return Err(ApiError::Internal);
+        }
+
+        // the retired addresses maybe still have late deposits
+        for retired in retired.iter().filter(|r| r.customer == customer.id) {
+            let (secret, eth) = generate_eth(retired.address_index, customer.id, mnemonics)
+                .map_err(|_| ApiError::Internal)?;
+            keys.push(ExportedKey {
+                customer: customer.account.clone(),
+                eth,
+                secret,
+            });
+        }
+        keys.push(ExportedKey {
+            customer: customer.account,
+            eth,
+            secret,
+        });
+    }
+
+    let plaintext = serde_json::to_vec(&keys).map_err(|_| ApiError::Internal)?;
+    let encrypted =
+        age::encrypt_and_armor(&recipient, &plaintext).map_err(|_| ApiError::Internal)?;
+    warn!(
+        "AUDIT: exported {} customer keys to recipient: {}",
+        keys.len(),
+        data.recipient
+    );
+
+    Ok(Json(json!({
+        "count": keys.len(),
+        "encrypted": encrypted,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_price() {
+        assert!(valid_price("0.01"));
+        assert!(valid_price("100"));
+        assert!(valid_price("0.0000001"));
+
+        assert!(!valid_price("0"));
+        assert!(!valid_price("0.00"));
+        assert!(!valid_price("1e3"));
+        assert!(!valid_price("inf"));
+        assert!(!valid_price("-1"));
+        assert!(!valid_price("+1"));
+        assert!(!valid_price(".5"));
+        assert!(!valid_price("5."));
+        assert!(!valid_price("1.2.3"));
+        assert!(!valid_price(""));
+    }
+}
@@ -1,5 +1,6 @@
 use axum::{
     Json,
+    http::StatusCode,
     response::{IntoResponse, Response},
 };
 
@@ -11,6 +12,7 @@ pub enum ApiError {
     Internal,
     UserAuth,
     NotFound,
+    RateLimited,
     Verify(String),
 }
 
@@ -34,18 +36,27 @@ impl From<sqlx::Error> for ApiError {
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
+        // the client should back off, so it is the only one with a non-200 status
+        let status = match self {
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::OK,
+        };
         let error = match self {
             Self::IO => "internal server error",
             Self::Internal => "internal error",
             Self::UserAuth => "user auth error",
             Self::NotFound => "not found",
+            Self::RateLimited => "too many requests",
             Self::Verify(msg) => &msg.into_boxed_str(),
         };
 
-        Json(serde_json::json!({
-            "status": "failure",
-            "error": error
-        }))
-        .into_response()
+        (
+            status,
+            Json(serde_json::json!({
+                "status": "failure",
+                "error": error
+            })),
+        )
+            .into_response()
     }
 }
@@ -14,19 +14,23 @@ use axum::{
     routing::{get, post},
 };
 use clap::Parser;
-use models::Storage;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use models::{Resources, Schedules, Storage, load_addresses_in_redis};
 use redis::Client as RedisClient;
-use scanner::{ChainType, ScannerConfig, ScannerMessage, ScannerService};
+use scanner::{
+    ChainConfig, ChainType, CustomerSigner, DEFAULT_RPC_TIMEOUT, MnemonicSigner, RemoteSigner,
+    ScannerConfig, ScannerMessage, ScannerService,
+};
 use sqlx::{
     any::Any as SqlxAny,
     migrate::MigrateDatabase,
     postgres::{PgPool, PgPoolOptions},
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{net::TcpListener, sync::mpsc::UnboundedSender};
 use tower_http::cors::{Any, CorsLayer};
 use tracing::level_filters::LevelFilter;
-use x402::{Evm8004Registry, EvmScheme, Facilitator};
+use x402::{Evm8004Registry, EvmAssetSpec, EvmScheme, Facilitator};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -43,9 +47,13 @@ struct Command {
     #[arg(long, env = "REDIS_URL", default_value = "redis://127.0.0.1:6379")]
     redis: String,
 
-    /// Account system mnemonics
+    /// Account system mnemonics, optional when the customer keys are managed by CUSTOMER_SIGNER
     #[arg(long, env = "MNEMONICS")]
-    mnemonics: String,
+    mnemonics: Option<String>,
+
+    /// External signing service of the customer keys, default is derived from the mnemonics
+    #[arg(long, env = "CUSTOMER_SIGNER")]
+    customer_signer: Option<String>,
 
     /// Main wallet which used to receive money
     #[arg(long, env = "WALLET")]
@@ -55,6 +63,10 @@ struct Command {
     #[arg(long, env = "APIKEY")]
     apikey: String,
 
+    /// Admin key for operator endpoints, admin endpoints are disabled if not set
+    #[arg(long, env = "ADMINKEY")]
+    adminkey: Option<String>,
+
     /// Webhook when new event emit
     #[arg(long, env = "WEBHOOK")]
     webhook: Option<String>,
@@ -70,16 +82,77 @@ struct Command {
     /// EIP-8004 registry agent identity contract
     #[arg(long, env = "AGENT_IDENTITY")]
     agent_identity: Option<String>,
+
+    /// Allow admin to export the derived customer keys (encrypted)
+    #[arg(long, env = "ALLOW_KEY_EXPORT", default_value_t = false)]
+    allow_key_export: bool,
+
+    /// Max concurrent redis batches when loading customer addresses at startup
+    #[arg(long, env = "ADDRESS_LOAD_CONCURRENCY", default_value_t = 8)]
+    address_load_concurrency: usize,
+
+    /// Policy of the deposits which match no session: forward (settle to wallet) or hold (until released)
+    #[arg(long, env = "UNMATCHED_POLICY", default_value = "forward", value_parser = ["forward", "hold"])]
+    unmatched_policy: String,
+
+    /// Sandbox mode, enable the test-deposit endpoint which simulates deposit and settlement
+    #[arg(long, env = "SANDBOX", default_value_t = false)]
+    sandbox: bool,
+
+    /// Acknowledge the wallet is a contract, which can receive the settlements
+    #[arg(long, env = "WALLET_CONTRACT_ACK", default_value_t = false)]
+    wallet_contract_ack: bool,
+
+    /// Seconds a rotated customer address is still scanned for the late deposits
+    #[arg(long, env = "ADDRESS_GRACE", default_value_t = 7 * 24 * 3600)]
+    address_grace: u64,
+
+    /// Seconds after a session expired, a late payment still matches it
+    #[arg(long, env = "SESSION_GRACE", default_value_t = 3600)]
+    session_grace: u64,
+
+    /// Max sessions created per minute, 0 is unlimited
+    #[arg(long, env = "SESSION_RATE_LIMIT", default_value_t = 600)]
+    session_rate_limit: u64,
+
+    /// Max blocks of one admin rescan request
+    #[arg(long, env = "RESCAN_MAX_BLOCKS", default_value_t = 10_000)]
+    rescan_max_blocks: u64,
+
+    /// Max x402 required amount in whole tokens, a larger one is a wrong decimals scale
+    #[arg(long, env = "X402_MAX_REQUIRED_TOKENS", default_value_t = x402::DEFAULT_MAX_REQUIRED_TOKENS)]
+    x402_max_required_tokens: u64,
+
+    /// Max seconds a future-dated x402 authorization can be scheduled, 0 is disabled
+    #[arg(long, env = "X402_MAX_SCHEDULE", default_value_t = 0)]
+    x402_max_schedule: u64,
+
+    /// Gasless relayer endpoint to submit x402 settlements, default is broadcast by the chain admin
+    #[arg(long, env = "X402_RELAYER")]
+    x402_relayer: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
     db: PgPool,
     redis: RedisClient,
-    mnemonics: String,
+    mnemonics: Option<String>,
+    signer: Arc<dyn CustomerSigner>,
     apikey: String,
+    adminkey: Option<String>,
+    webhook: Option<String>,
+    wallet: String,
+    sandbox: bool,
+    allow_key_export: bool,
+    address_grace: u64,
+    session_rate_limit: u64,
+    session_grace: u64,
+    rescan_max_blocks: u64,
+    config: api::ConfigResponse,
+    chains: Vec<ChainConfig>,
     facilitator: Arc<Facilitator>,
-    _sender: UnboundedSender<ScannerMessage>,
+    metrics: PrometheusHandle,
+    sender: UnboundedSender<ScannerMessage>,
 }
 
 #[tokio::main]
@@ -93,6 +166,27 @@ async fn main() {
     let args = Command::parse();
     let scanner_str = std::fs::read_to_string(&args.scanner_config).unwrap();
     let scanner_config: ScannerConfig = toml::from_str(&scanner_str).unwrap();
+    let signer: Arc<dyn CustomerSigner> = match (&args.customer_signer, &args.mnemonics) {
+        (Some(url), _) => match RemoteSigner::new(url, DEFAULT_RPC_TIMEOUT) {
+            Ok(signer) => Arc::new(signer),
+            Err(err) => {
+                error!("🔥 Invalid customer signer: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(mnemonics)) => Arc::new(MnemonicSigner::new(mnemonics.clone())),
+        (None, None) => {
+            error!("🔥 MNEMONICS or CUSTOMER_SIGNER is required");
+            std::process::exit(1);
+        }
+    };
+    let config = api::ConfigResponse::new(
+        &scanner_config,
+        &args.wallet,
+        args.webhook.as_deref(),
+        args.agent_id,
+        args.agent_identity.as_deref(),
+    );
 
     // setup database & init
     let _ = SqlxAny::create_database(&args.database).await;
@@ -127,21 +221,60 @@ async fn main() {
         }
     };
 
+    // load customer addresses to redis for scanning
+    match load_addresses_in_redis(
+        &redis,
+        &db,
+        args.address_load_concurrency,
+        args.address_grace,
+    )
+    .await
+    {
+        Ok(count) => info!("✅ Loaded {} customer addresses to Redis!", count),
+        Err(err) => {
+            error!("🔥 Failed to load customer addresses: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // setup prometheus metrics recorder, rpc timings are exported as histograms
+    let metrics = match PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_owned()),
+            &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0],
+        )
+        .and_then(|builder| builder.install_recorder())
+    {
+        Ok(handle) => handle,
+        Err(err) => {
+            error!("🔥 Failed to install the metrics recorder: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
     // running listening chain & tokens
     let storage = Storage {
         db: db.clone(),
         redis: redis.clone(),
         apikey: args.apikey.clone(),
-        webhook: args.webhook,
-        wallet: args.wallet,
+        webhook: args.webhook.clone(),
+        wallet: args.wallet.clone(),
+        sandbox: false,
+        hold_unmatched: args.unmatched_policy == "hold",
+        session_grace: args.session_grace,
     };
-    let (_sender, x402_assets) =
-        ScannerService::new(storage, args.mnemonics.clone(), scanner_config)
-            .await
-            .unwrap()
-            .run()
-            .await
-            .unwrap();
+    let chains = scanner_config.chains.clone();
+    let scanner = ScannerService::new(storage, signer.clone(), scanner_config)
+        .await
+        .unwrap();
+    if let Err(err) = scanner
+        .check_wallet(&args.wallet, args.wallet_contract_ack)
+        .await
+    {
+        error!("🔥 Failed to check the wallet: {:?}", err);
+        std::process::exit(1);
+    }
+    let (sender, x402_assets) = scanner.run().await.unwrap();
 
     // building x402 facilitator
     let agent = match (args.agent_id, args.agent_identity) {
@@ -149,28 +282,81 @@ async fn main() {
         _ => None,
     };
     let mut facilitator = Facilitator::new();
+    facilitator.storage(Resources { db: db.clone() });
+    facilitator.schedule_storage(Schedules {
+        redis: redis.clone(),
+    });
     for c in x402_assets {
         match c.ctype {
             ChainType::Evm => {
                 let mut scheme = EvmScheme::new(&c.rpc, &c.network, &c.signer, agent.clone())
                     .await
                     .unwrap();
-                for asset in c.assets {
-                    // try x402 asset
-                    scheme.asset(&asset.address).await.unwrap();
+                scheme.timeout(c.timeout).unwrap();
+                scheme.schedule(args.x402_max_schedule);
+                scheme.max_assets(c.max_tokens);
+                scheme.max_required_tokens(args.x402_max_required_tokens);
+                scheme.nonce_lock(c.nonce_lock.clone());
+                scheme.max_gas_price(c.max_gas_price);
+                scheme.gas(c.gas);
+                if let Some(relayer) = &args.x402_relayer {
+                    scheme.relayer(relayer);
+                }
+                // try x402 assets, the failed ones are not accepted for x402
+                let specs: Vec<EvmAssetSpec> = c
+                    .assets
+                    .iter()
+                    .map(|asset| EvmAssetSpec {
+                        address: asset.address.clone(),
+                        decimals: Some(asset.decimal),
+                        skip_probe: c.skip_probe,
+                    })
+                    .collect();
+                let (registered, failures) = scheme.try_assets(&specs).await;
+                for (address, err) in failures {
+                    error!(
+                        "🔥 {}: Failed to register x402 asset {}: {:?}",
+                        c.network, address, err
+                    );
                 }
+                info!("✅ {}: Registered x402 assets: {:?}", c.network, registered);
                 facilitator.register(scheme);
             }
         }
     }
 
+    // settle the scheduled x402 authorizations when they are due
+    let facilitator = Arc::new(facilitator);
+    if args.x402_max_schedule > 0 {
+        let facilitator = facilitator.clone();
+        tokio::spawn(async move {
+            loop {
+                facilitator.settle_due().await;
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
     let app_state = Arc::new(AppState {
-        _sender,
+        sender,
         db,
         redis,
-        facilitator: Arc::new(facilitator),
+        facilitator,
+        metrics,
         apikey: args.apikey,
+        adminkey: args.adminkey,
+        webhook: args.webhook,
+        wallet: args.wallet,
+        sandbox: args.sandbox,
+        allow_key_export: args.allow_key_export,
+        address_grace: args.address_grace,
+        session_rate_limit: args.session_rate_limit,
+        session_grace: args.session_grace,
+        rescan_max_blocks: args.rescan_max_blocks,
+        config,
+        chains,
         mnemonics: args.mnemonics,
+        signer,
     });
 
     let cors = CorsLayer::new()
@@ -181,10 +367,28 @@ async fn main() {
     let router = Router::new()
         .route("/sessions", post(api::create_session))
         .route("/sessions/{id}", get(api::get_session))
+        .route("/estimate", get(api::estimate))
+        .route(
+            "/customers/{account}/rotate",
+            post(api::rotate_customer_address),
+        )
+        .route("/sandbox/deposits", post(api::sandbox_deposit))
+        .route("/deposits/{id}/release", post(api::release_deposit))
+        .route("/merchants/replay", post(api::replay_webhooks))
         .route("/x402/requirements", get(api::x402_requirements))
+        .route("/x402/quote", get(api::x402_quote))
         .route("/x402/payments", post(api::x402_payment))
+        .route("/x402/verify", post(api::x402_verify))
+        .route("/x402/settle", post(api::x402_settle))
         .route("/x402/support", get(api::x402_support))
         .route("/x402/discovery", get(api::x402_discovery))
+        .route("/x402/resources", post(api::x402_resource))
+        .route("/metrics", get(api::metrics))
+        .route("/admin/config", get(api::admin_config))
+        .route("/admin/rescan", post(api::admin_rescan))
+        .route("/admin/chains/{name}/pause", post(api::admin_pause_chain))
+        .route("/admin/chains/{name}/resume", post(api::admin_resume_chain))
+        .route("/admin/keys/export", post(api::admin_export_keys))
         .with_state(app_state)
         .layer(cors);
 
@@ -1,6 +1,6 @@
 use crate::error::{ApiError, Result};
 use chrono::prelude::*;
-use scanner::generate_eth;
+use scanner::CustomerSigner;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
@@ -10,6 +10,19 @@ pub struct Customer {
     pub account: String,
     pub eth: String,
     pub updated_at: NaiveDateTime,
+    /// the derivation index of the active deposit address, increased when rotating
+    pub address_index: i32,
+    /// the duplicate account kept for its deposit address, the account resolves to this id
+    pub alias_of: Option<i32>,
+}
+
+/// The retired deposit address, still scanned for the late deposits in the grace period
+#[derive(Serialize, Deserialize)]
+pub struct RetiredAddress {
+    pub eth: String,
+    pub customer: i32,
+    pub address_index: i32,
+    pub retired_at: NaiveDateTime,
 }
 
 impl Customer {
@@ -22,49 +35,166 @@ impl Customer {
     }
 
     pub async fn get_by_account(account: &str, db: &PgPool) -> Result<Self> {
-        let res = query_as!(Self, "SELECT * FROM customers WHERE account=$1", account)
-            .fetch_one(db)
+        let res = query_as!(
+            Self,
+            "SELECT * FROM customers WHERE account=$1 AND alias_of IS NULL",
+            account
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(res)
+    }
+
+    pub async fn list_all(db: &PgPool) -> Result<Vec<Self>> {
+        let res = query_as!(Self, "SELECT * FROM customers ORDER BY id")
+            .fetch_all(db)
             .await?;
 
         Ok(res)
     }
 
-    /// get or insert the account by given account
-    pub async fn get_or_insert(account: String, db: &PgPool, mem: &str) -> Result<Self> {
-        if let Ok(mut a) = Self::get_by_account(&account, db).await {
-            // check customer has pay account
-            if a.eth.is_empty() {
-                let (_, eth) = generate_eth(0, a.id, mem).map_err(|_err| ApiError::Internal)?;
-                a.eth = eth;
-                let _ = query!("UPDATE customers SET eth=$1 WHERE id=$2", a.eth, a.id)
-                    .execute(db)
+    /// list the (id, eth, address_index) of customers which id > after, order by id
+    pub async fn list_addresses(
+        after: i32,
+        limit: i64,
+        db: &PgPool,
+    ) -> Result<Vec<(i32, String, i32)>> {
+        let res = query!(
+            "SELECT id, eth, address_index FROM customers WHERE id>$1 AND eth<>'' ORDER BY id LIMIT $2",
+            after,
+            limit
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(res
+            .into_iter()
+            .map(|r| (r.id, r.eth, r.address_index))
+            .collect())
+    }
+
+    /// retire the active deposit address, and derive the next index as the new one,
+    /// return the customer with new address and the retired address
+    pub async fn rotate(
+        account: &str,
+        db: &PgPool,
+        signer: &dyn CustomerSigner,
+    ) -> Result<(Self, RetiredAddress)> {
+        let now = Utc::now().naive_utc();
+
+        // lock the customer, so the concurrent rotations will not derive same index
+        let mut tx = db.begin().await?;
+        let old = query_as!(
+            Self,
+            "SELECT * FROM customers WHERE account=$1 AND alias_of IS NULL FOR UPDATE",
+            account
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if old.eth.is_empty() {
+            return Err(ApiError::Verify(
+                "customer has no deposit address".to_owned(),
+            ));
+        }
+
+        let index = old.address_index + 1;
+        let eth = signer
+            .address(index, old.id)
+            .await
+            .map_err(|_err| ApiError::Internal)?;
+        let retired = query_as!(
+            RetiredAddress,
+            "INSERT INTO retired_addresses(eth,customer,address_index,retired_at) VALUES ($1,$2,$3,$4) RETURNING *",
+            old.eth,
+            old.id,
+            old.address_index,
+            now
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let customer = query_as!(
+            Self,
+            "UPDATE customers SET eth=$1, address_index=$2, updated_at=$3 WHERE id=$4 RETURNING *",
+            eth,
+            index,
+            now,
+            old.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok((customer, retired))
+    }
+
+    /// get or insert the account by given account, concurrent calls get the same row
+    pub async fn get_or_insert(
+        account: String,
+        db: &PgPool,
+        signer: &dyn CustomerSigner,
+    ) -> Result<Self> {
+        let mut a = match Self::get_by_account(&account, db).await {
+            Ok(a) => a,
+            Err(_) => {
+                // reserve the id first, so the pay account is derived before insert
+                let id = query_scalar!(r#"SELECT nextval('customers_id_seq')::INT AS "id!""#)
+                    .fetch_one(db)
                     .await?;
-            }
+                let eth = signer
+                    .address(0, id)
+                    .await
+                    .map_err(|_err| ApiError::Internal)?;
+                let now = Utc::now().naive_utc();
 
-            Ok(a)
-        } else {
-            let now = Utc::now().naive_utc();
-            let id = query_scalar!(
-                "INSERT INTO customers(account,eth,updated_at) VALUES ($1,$2,$3) RETURNING id",
-                account,
-                "",
-                now
-            )
-            .fetch_one(db)
-            .await?;
+                // if a concurrent request inserted the account, return its row
+                query_as!(
+                    Self,
+                    "INSERT INTO customers(id,account,eth,updated_at) VALUES ($1,$2,$3,$4) ON CONFLICT (account) WHERE alias_of IS NULL DO UPDATE SET account=EXCLUDED.account RETURNING *",
+                    id,
+                    account,
+                    eth,
+                    now
+                )
+                .fetch_one(db)
+                .await?
+            }
+        };
 
-            let (_, eth) = generate_eth(0, id, mem).map_err(|_err| ApiError::Internal)?;
-            // Add more accounts
-            let _ = query!("UPDATE customers SET eth=$1 WHERE id=$2", eth, id)
+        // check customer has pay account
+        if a.eth.is_empty() {
+            a.eth = signer
+                .address(a.address_index, a.id)
+                .await
+                .map_err(|_err| ApiError::Internal)?;
+            let _ = query!("UPDATE customers SET eth=$1 WHERE id=$2", a.eth, a.id)
                 .execute(db)
                 .await?;
-
-            Ok(Self {
-                id,
-                account,
-                eth,
-                updated_at: now,
-            })
         }
+
+        Ok(a)
+    }
+}
+
+impl RetiredAddress {
+    /// list the addresses retired after the given time, which are still in the grace period
+    pub async fn list_after(after: NaiveDateTime, db: &PgPool) -> Result<Vec<Self>> {
+        let res = query_as!(
+            Self,
+            "SELECT * FROM retired_addresses WHERE retired_at>$1 ORDER BY retired_at",
+            after
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(res)
+    }
+
+    pub async fn list_all(db: &PgPool) -> Result<Vec<Self>> {
+        let res = query_as!(Self, "SELECT * FROM retired_addresses ORDER BY customer")
+            .fetch_all(db)
+            .await?;
+
+        Ok(res)
     }
 }
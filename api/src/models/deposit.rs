@@ -1,5 +1,6 @@
-use crate::error::Result;
+use crate::error::{ApiError, Result};
 use chrono::prelude::*;
+use scanner::SettlementStatus;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
@@ -13,6 +14,12 @@ pub struct Deposit {
     pub settled_amount: Option<i32>,
     pub settled_tx: Option<String>,
     pub settled_at: Option<NaiveDateTime>,
+    pub status: String,
+    pub asset: String,
+    /// the derivation index of the deposit address
+    pub address_index: i32,
+    /// the log index of the token transfer, none is the whole transaction
+    pub log_index: Option<i64>,
 }
 
 impl Deposit {
@@ -24,16 +31,64 @@ impl Deposit {
         Ok(res)
     }
 
-    pub async fn insert(customer: i32, amount: i32, tx: String, db: &PgPool) -> Result<i32> {
+    /// list the deposits created in [from, to) after the id, order by id (the created order)
+    pub async fn list_between(
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        after_id: i32,
+        limit: i64,
+        db: &PgPool,
+    ) -> Result<Vec<Self>> {
+        let res = query_as!(
+            Self,
+            "SELECT * FROM deposits WHERE created_at>=$1 AND created_at<$2 AND id>$3 ORDER BY id LIMIT $4",
+            from,
+            to,
+            after_id,
+            limit
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(res)
+    }
+
+    /// the transfer is deposited, a deposit without log index is the whole transaction
+    pub async fn exists(tx: &str, log_index: Option<i64>, db: &PgPool) -> Result<bool> {
+        let res = query_scalar!(
+            "SELECT id FROM deposits WHERE tx=$1 AND (log_index IS NULL OR $2::BIGINT IS NULL OR log_index=$2) LIMIT 1",
+            tx,
+            log_index
+        )
+        .fetch_optional(db)
+        .await?;
+
+        Ok(res.is_some())
+    }
+
+    pub async fn insert(
+        customer: i32,
+        amount: i32,
+        tx: String,
+        asset: &str,
+        address_index: i32,
+        log_index: Option<i64>,
+        db: &PgPool,
+    ) -> Result<Option<i32>> {
+        // None if the transfer (tx and log index) had been inserted
         let now = Utc::now().naive_utc();
         let id = query_scalar!(
-            "INSERT INTO deposits(customer,amount,tx,created_at) VALUES ($1,$2,$3,$4) RETURNING id",
+            "INSERT INTO deposits(customer,amount,tx,created_at,status,asset,address_index,log_index) VALUES ($1,$2,$3,$4,$5,$6,$7,$8) ON CONFLICT DO NOTHING RETURNING id",
             customer,
             amount,
             tx,
             now,
+            SettlementStatus::Detected.as_str(),
+            asset,
+            address_index,
+            log_index,
         )
-        .fetch_one(db)
+        .fetch_optional(db)
         .await?;
 
         Ok(id)
@@ -42,10 +97,11 @@ impl Deposit {
     pub async fn settle(id: i32, amount: i32, tx: String, db: &PgPool) -> Result<()> {
         let now = Utc::now().naive_utc();
         let _ = query!(
-            "UPDATE deposits SET settled_amount=$1,settled_tx=$2,settled_at=$3 WHERE id=$4",
+            "UPDATE deposits SET settled_amount=$1,settled_tx=$2,settled_at=$3,status=$4 WHERE id=$5",
             amount,
             tx,
             now,
+            SettlementStatus::Settled.as_str(),
             id
         )
         .execute(db)
@@ -53,4 +109,40 @@ impl Deposit {
 
         Ok(())
     }
+
+    pub async fn set_status(id: i32, status: SettlementStatus, db: &PgPool) -> Result<()> {
+        let _ = query!(
+            "UPDATE deposits SET status=$1 WHERE id=$2",
+            status.as_str(),
+            id
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// claim the held deposit for release as settling, None if it is not held,
+    /// so the concurrent releases settle it only once
+    pub async fn claim_held(id: i32, db: &PgPool) -> Result<Option<Self>> {
+        let res = query_as!(
+            Self,
+            "UPDATE deposits SET status=$1 WHERE id=$2 AND status=$3 RETURNING *",
+            SettlementStatus::Settling.as_str(),
+            id,
+            SettlementStatus::Held.as_str()
+        )
+        .fetch_optional(db)
+        .await?;
+
+        Ok(res)
+    }
+
+    /// the settlement status, an unknown one is an error, never reported as another state
+    pub fn settlement_status(&self) -> Result<SettlementStatus> {
+        self.status.parse().map_err(|e| {
+            error!("deposit {}: {}", self.id, e);
+            ApiError::Internal
+        })
+    }
 }
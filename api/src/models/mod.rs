@@ -1,17 +1,34 @@
 mod chain;
 mod customer;
 mod deposit;
+mod resource;
 mod session;
 
 pub use chain::ChainBlock;
-pub use customer::Customer;
+pub use customer::{Customer, RetiredAddress};
 pub use deposit::Deposit;
+pub use resource::Resource;
 pub use session::Session;
 
 use anyhow::Result;
+use chrono::{TimeDelta, Utc};
 use redis::{AsyncCommands, Client as RedisClient};
-use scanner::ScannerEvent;
+use scanner::{NATIVE_ASSET, ScannerEvent, SettlementStatus};
 use sqlx::PgPool;
+use tokio::task::JoinSet;
+use x402::{DiscoveryItem, ResourceStorage, ScheduleStorage, VerifyRequest};
+
+/// Customer address cache expiration, 30 days
+pub const ADDRESS_TTL: u64 = 30 * 24 * 3600;
+
+/// Customer addresses loaded in one redis pipeline
+const ADDRESS_BATCH: i64 = 1000;
+
+/// Max tries of the webhook sequence, the event is not sent without it
+const SEQUENCE_TRIES: u64 = 3;
+
+/// Max tries of saving a deposit when the database is unavailable
+const DEPOSIT_TRIES: u64 = 3;
 
 pub struct Storage {
     pub db: PgPool,
@@ -19,6 +36,87 @@ pub struct Storage {
     pub apikey: String,
     pub webhook: Option<String>,
     pub wallet: String,
+    pub sandbox: bool,
+    /// hold the deposits which match no session, until merchant release them
+    pub hold_unmatched: bool,
+    /// seconds after the session expired, the late payment still matches it
+    pub session_grace: u64,
+}
+
+impl Storage {
+    // send the event with the next sequence, so the merchant can order the events
+    // even when the deliveries are out of order
+    async fn send_event(&self, webhook: &str, event: ScannerEvent) -> Result<()> {
+        self.send_event_as(webhook, event, self.sandbox).await
+    }
+
+    /// send the event with the next sequence, and the given sandbox mark
+    pub async fn send_event_as(
+        &self,
+        webhook: &str,
+        event: ScannerEvent,
+        sandbox: bool,
+    ) -> Result<()> {
+        let sequence = self.next_sequence().await?;
+        event.send(webhook, &self.apikey, sandbox, sequence).await
+    }
+
+    // the next webhook sequence, retried when the database is briefly unavailable
+    async fn next_sequence(&self) -> Result<i64> {
+        for i in 1..=SEQUENCE_TRIES {
+            match query_scalar!(r#"SELECT nextval('webhook_sequence') AS "sequence!""#)
+                .fetch_one(&self.db)
+                .await
+            {
+                Ok(sequence) => return Ok(sequence),
+                Err(err) => {
+                    error!("Webhook sequence failed ({i}/{SEQUENCE_TRIES}): {err}");
+                    if i < SEQUENCE_TRIES {
+                        tokio::time::sleep(std::time::Duration::from_millis(100 * i)).await;
+                    }
+                }
+            }
+        }
+        Err(anyhow::anyhow!("No webhook sequence"))
+    }
+
+    /// the paid and the settled (if settled) events of the deposit, in order,
+    /// the sandbox deposits are marked with sandbox
+    pub async fn replay_events(&self, deposit: &Deposit) -> Result<Vec<ScannerEvent>> {
+        let customer = Customer::get(deposit.customer, &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Not found"))?;
+        let mut events = vec![];
+        match Session::get_by_deposit(deposit.id, &self.db).await {
+            Ok(session) => {
+                let account = customer.account;
+                events.push(if session.expired_at < deposit.created_at {
+                    ScannerEvent::SessionPaidLate(session.id, account.clone(), deposit.amount)
+                } else {
+                    ScannerEvent::SessionPaid(session.id, account.clone(), deposit.amount)
+                });
+                if let Some(settled) = deposit.settled_amount {
+                    events.push(ScannerEvent::SessionSettled(session.id, account, settled));
+                }
+            }
+            Err(_) => {
+                events.push(ScannerEvent::UnknowPaid(
+                    customer.account.clone(),
+                    deposit.amount,
+                    deposit.asset.clone(),
+                ));
+                if let Some(settled) = deposit.settled_amount {
+                    events.push(ScannerEvent::UnknowSettled(
+                        customer.account,
+                        settled,
+                        deposit.asset.clone(),
+                    ));
+                }
+            }
+        }
+
+        Ok(events)
+    }
 }
 
 impl scanner::ScannerStorage for Storage {
@@ -39,14 +137,22 @@ impl scanner::ScannerStorage for Storage {
             return Err(anyhow::anyhow!("No address: {address}"));
         }
 
-        let id: i32 = conn.get(&key).await?;
-        Ok((0, id, self.wallet.clone()))
+        // the value is `id:index`, the derivation index is the mid, old values only have id
+        let value: String = conn.get(&key).await?;
+        let (id, index) = value.split_once(':').unwrap_or((&value, "0"));
+        Ok((index.parse()?, id.parse()?, self.wallet.clone()))
     }
 
-    async fn no_transaction(&self, tx: &str) -> Result<()> {
-        let key = format!("zpt:{}", tx);
+    async fn no_transaction(&self, tx: &str, log_index: Option<u64>) -> Result<()> {
+        let key = transaction_key(tx, log_index);
         let mut conn = self.redis.get_multiplexed_async_connection().await?;
-        if conn.exists(&key).await? {
+        // redis cache only keep 1 day, check database for the rescanned old blocks
+        let log_index = log_index.map(|i| i as i64);
+        if conn.exists(&key).await?
+            || Deposit::exists(tx, log_index, &self.db)
+                .await
+                .unwrap_or(false)
+        {
             Err(anyhow::anyhow!("Had transaction"))
         } else {
             Ok(())
@@ -55,21 +161,52 @@ impl scanner::ScannerStorage for Storage {
 
     async fn deposited(
         &self,
-        _identity: String,
-        _mid: i32,
+        identity: String,
+        mid: i32,
         cid: i32,
         amount: i32,
         tx: String,
+        log_index: Option<u64>,
     ) -> Result<i32> {
-        // 1. Save the deposit to the database
-        let did = Deposit::insert(cid, amount, tx.clone(), &self.db)
+        // 1. Save the deposit to the database, the mid is the derivation index of the address,
+        // the same transfer is inserted once by the unique tx and log index
+        let mut did = None;
+        for i in 1..=DEPOSIT_TRIES {
+            match Deposit::insert(
+                cid,
+                amount,
+                tx.clone(),
+                &identity,
+                mid,
+                log_index.map(|i| i as i64),
+                &self.db,
+            )
             .await
-            .unwrap_or_default();
+            {
+                Ok(Some(id)) => {
+                    did = Some(id);
+                    break;
+                }
+                Ok(None) => return Err(anyhow::anyhow!("Had transaction")),
+                Err(err) => {
+                    error!("Deposit {tx} save failed ({i}/{DEPOSIT_TRIES}): {err:?}");
+                    if i < DEPOSIT_TRIES {
+                        tokio::time::sleep(std::time::Duration::from_millis(100 * i)).await;
+                    }
+                }
+            }
+        }
+        let did = did.ok_or(anyhow::anyhow!("Deposit {tx} not saved"))?;
 
-        // 2. fetch the right session and update it
-        let sessions = Session::list_unused(cid, &self.db)
-            .await
-            .unwrap_or_default();
+        // 2. fetch the right session and update it,
+        // session amounts are stablecoin, so native deposits never pay a session
+        let sessions = if identity.ends_with(NATIVE_ASSET) {
+            vec![]
+        } else {
+            Session::list_unused(cid, self.session_grace, &self.db)
+                .await
+                .unwrap_or_default()
+        };
         let mut used_session = None;
         for session in sessions {
             if session.amount == amount {
@@ -84,27 +221,31 @@ impl scanner::ScannerStorage for Storage {
             && let Ok(customer) = Customer::get(cid, &self.db).await
         {
             if let Some(session) = &used_session {
-                if ScannerEvent::SessionPaid(session.id, customer.account, amount)
-                    .send(webhook, &self.apikey)
-                    .await
-                    .is_ok()
-                {
+                let event = if session.expired_at < Utc::now().naive_utc() {
+                    ScannerEvent::SessionPaidLate(session.id, customer.account, amount)
+                } else {
+                    ScannerEvent::SessionPaid(session.id, customer.account, amount)
+                };
+                if self.send_event(webhook, event).await.is_ok() {
                     let _ = session.sent(&self.db).await;
                 }
             } else {
-                let _ = ScannerEvent::UnknowPaid(customer.account, amount)
-                    .send(webhook, &self.apikey)
+                let _ = self
+                    .send_event(
+                        webhook,
+                        ScannerEvent::UnknowPaid(customer.account, amount, identity),
+                    )
                     .await;
             }
         }
 
         // 4. save transaction to redis
-        let _ = store_transaction_in_redis(&self.redis, &tx).await;
+        let _ = store_transaction_in_redis(&self.redis, &transaction_key(&tx, log_index)).await;
 
         Ok(did)
     }
 
-    async fn settled(&self, _identity: String, did: i32, amount: i32, tx: String) -> Result<()> {
+    async fn settled(&self, identity: String, did: i32, amount: i32, tx: String) -> Result<()> {
         // 1. Save settled to deposit
         let _ = Deposit::settle(did, amount, tx, &self.db).await;
         let deposit = Deposit::get(did, &self.db)
@@ -118,42 +259,316 @@ impl scanner::ScannerStorage for Storage {
         // 2. webhook settled event
         if let Some(webhook) = &self.webhook {
             if let Ok(session) = &used_session {
-                let _ = ScannerEvent::SessionSettled(session.id, customer.account, amount)
-                    .send(webhook, &self.apikey)
+                let _ = self
+                    .send_event(
+                        webhook,
+                        ScannerEvent::SessionSettled(session.id, customer.account, amount),
+                    )
                     .await;
             } else {
-                let _ = ScannerEvent::UnknowSettled(customer.account, amount)
-                    .send(webhook, &self.apikey)
+                let _ = self
+                    .send_event(
+                        webhook,
+                        ScannerEvent::UnknowSettled(customer.account, amount, identity),
+                    )
                     .await;
             }
         }
 
         Ok(())
     }
+
+    async fn set_status(&self, did: i32, status: SettlementStatus) -> Result<()> {
+        Deposit::set_status(did, status, &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Not found"))
+    }
+
+    async fn hold(&self, did: i32) -> Result<bool> {
+        if !self.hold_unmatched || Session::get_by_deposit(did, &self.db).await.is_ok() {
+            return Ok(false);
+        }
+
+        self.set_status(did, SettlementStatus::Held).await?;
+        Ok(true)
+    }
+
+    async fn status(&self, did: i32) -> Result<SettlementStatus> {
+        let deposit = Deposit::get(did, &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Not found"))?;
+        deposit.status.parse()
+    }
+}
+
+/// The discoverable x402 resources persisted in database
+pub struct Resources {
+    pub db: PgPool,
 }
 
-// Store customer address in Redis for fast lookup during scanning
-pub async fn store_address_in_redis(redis: &RedisClient, eth: &str, id: i32) -> Result<()> {
+#[async_trait::async_trait]
+impl ResourceStorage for Resources {
+    async fn save_resource(&self, item: DiscoveryItem) -> Result<()> {
+        Resource::upsert(&item, &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to save resource"))
+    }
+
+    async fn list_resources(
+        &self,
+        r#type: Option<String>,
+        limit: i32,
+        offset: i32,
+    ) -> Result<(Vec<DiscoveryItem>, i32)> {
+        let total = Resource::count(r#type.clone(), &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to count resources"))?;
+        let items = Resource::list(r#type, limit as i64, offset as i64, &self.db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to list resources"))?
+            .into_iter()
+            .map(|r| r.into_item())
+            .collect();
+
+        Ok((items, total as i32))
+    }
+}
+
+/// The scheduled x402 settlements persisted in redis until the authorization expired,
+/// the due index is a sorted set by the settle time
+pub struct Schedules {
+    pub redis: RedisClient,
+}
+
+const SCHEDULE_DUE_KEY: &str = "zps:due";
+
+fn schedule_key(req: &VerifyRequest) -> String {
+    format!("zps:{}", req.authorization_id())
+}
+
+#[async_trait::async_trait]
+impl ScheduleStorage for Schedules {
+    async fn save_schedule(&self, req: &VerifyRequest, at: u64) -> Result<bool> {
+        let valid_before: u64 = req
+            .payment_payload
+            .payload
+            .authorization
+            .valid_before
+            .parse()?;
+        let now = Utc::now().timestamp() as u64;
+        let ttl = valid_before.saturating_sub(now).max(1);
+
+        let key = schedule_key(req);
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let saved: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(serde_json::to_string(req)?)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl)
+            .query_async(&mut conn)
+            .await?;
+        if saved.is_none() {
+            return Ok(false);
+        }
+        let _: () = conn.zadd(SCHEDULE_DUE_KEY, key, at).await?;
+        Ok(true)
+    }
+
+    async fn due_schedules(&self, now: u64) -> Result<Vec<VerifyRequest>> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let keys: Vec<String> = conn.zrangebyscore(SCHEDULE_DUE_KEY, 0, now).await?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let values: Vec<Option<String>> = conn.mget(&keys).await?;
+        let mut due = vec![];
+        for (key, value) in keys.iter().zip(values) {
+            match value.and_then(|v| serde_json::from_str(&v).ok()) {
+                Some(req) => due.push(req),
+                // the authorization expired, nothing to settle
+                None => {
+                    let _: () = conn.zrem(SCHEDULE_DUE_KEY, key).await?;
+                }
+            }
+        }
+        Ok(due)
+    }
+
+    async fn claim_schedule(&self, req: &VerifyRequest) -> Result<bool> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let removed: usize = conn.zrem(SCHEDULE_DUE_KEY, schedule_key(req)).await?;
+        Ok(removed > 0)
+    }
+
+    async fn retry_schedule(&self, req: &VerifyRequest, at: u64) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = conn.zadd(SCHEDULE_DUE_KEY, schedule_key(req), at).await?;
+        Ok(())
+    }
+
+    async fn finish_schedule(&self, req: &VerifyRequest) -> Result<()> {
+        let mut conn = self.redis.get_multiplexed_async_connection().await?;
+        let _: () = conn.del(schedule_key(req)).await?;
+        Ok(())
+    }
+}
+
+// Store customer address and its derivation index in Redis for fast lookup during scanning,
+// the retired addresses expire after the grace period
+pub async fn store_address_in_redis(
+    redis: &RedisClient,
+    eth: &str,
+    id: i32,
+    index: i32,
+    ttl: u64,
+) -> Result<()> {
     let mut conn = redis.get_multiplexed_async_connection().await?;
 
     let key = format!("zpc:{}", eth);
-
-    // Set expiration to 30 days
-    let _: () = conn.set_ex(&key, id, 30 * 24 * 3600).await?;
+    let _: () = conn.set_ex(&key, format!("{id}:{index}"), ttl).await?;
 
     debug!("Stored customer address in Redis: {}", eth);
     Ok(())
 }
 
-// Store tranaction in Redis for avoid duplicate
-async fn store_transaction_in_redis(redis: &RedisClient, tx: &str) -> Result<()> {
+// Load all customer addresses to Redis at startup, use pipeline for every batch,
+// and at most `concurrency` batches are in flight. The retired addresses in the
+// `grace` seconds are loaded with their remaining time
+pub async fn load_addresses_in_redis(
+    redis: &RedisClient,
+    db: &PgPool,
+    concurrency: usize,
+    grace: u64,
+) -> Result<usize> {
+    let mut conn = redis.get_multiplexed_async_connection().await?;
+    let mut tasks = JoinSet::new();
+    let mut after = 0;
+    let mut loaded = 0;
+
+    let now = Utc::now().naive_utc();
+    let since = now - TimeDelta::seconds(grace as i64);
+    let retired = RetiredAddress::list_after(since, db)
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to list retired addresses"))?;
+    if !retired.is_empty() {
+        loaded += retired.len();
+        let mut pipe = redis::pipe();
+        for r in retired {
+            let ttl = (r.retired_at - since).num_seconds().max(1) as u64;
+            pipe.set_ex(
+                format!("zpc:{}", r.eth),
+                format!("{}:{}", r.customer, r.address_index),
+                ttl,
+            )
+            .ignore();
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+    }
+
+    loop {
+        let addresses = Customer::list_addresses(after, ADDRESS_BATCH, db)
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to list customer addresses"))?;
+        let Some((last, _, _)) = addresses.last() else {
+            break;
+        };
+        after = *last;
+        loaded += addresses.len();
+
+        let mut pipe = redis::pipe();
+        for (id, eth, index) in addresses {
+            pipe.set_ex(format!("zpc:{}", eth), format!("{id}:{index}"), ADDRESS_TTL)
+                .ignore();
+        }
+
+        if tasks.len() >= concurrency.max(1)
+            && let Some(res) = tasks.join_next().await
+        {
+            res??;
+        }
+        let mut conn = conn.clone();
+        tasks.spawn(async move { pipe.query_async::<()>(&mut conn).await });
+    }
+
+    while let Some(res) = tasks.join_next().await {
+        res??;
+    }
+
+    Ok(loaded)
+}
+
+// The verified x402 payment key, by the payer and nonce of the authorization
+fn verified_key(req: &VerifyRequest) -> String {
+    let auth = &req.payment_payload.payload.authorization;
+    format!(
+        "zpv:{}:{}",
+        auth.from.to_lowercase(),
+        auth.nonce.to_lowercase()
+    )
+}
+
+// Cache the verified x402 payment until the authorization is expired,
+// so the later settle of the same payment does not verify again
+pub async fn store_verified_in_redis(redis: &RedisClient, req: &VerifyRequest) -> Result<()> {
+    let valid_before: u64 = req
+        .payment_payload
+        .payload
+        .authorization
+        .valid_before
+        .parse()?;
+    let now = Utc::now().timestamp() as u64;
+    if valid_before <= now {
+        return Ok(());
+    }
+
+    let mut conn = redis.get_multiplexed_async_connection().await?;
+    let value = serde_json::to_string(req)?;
+    let _: () = conn
+        .set_ex(verified_key(req), value, valid_before - now)
+        .await?;
+    Ok(())
+}
+
+// Take the cached verification, it is valid only when the payment is the same as verified,
+// and one verification is used by one settle
+pub async fn take_verified_in_redis(redis: &RedisClient, req: &VerifyRequest) -> Result<bool> {
     let mut conn = redis.get_multiplexed_async_connection().await?;
+    let value: Option<String> = conn.get_del(verified_key(req)).await?;
+    Ok(value.is_some_and(|v| serde_json::to_string(req).is_ok_and(|r| r == v)))
+}
 
-    let key = format!("zpt:{}", tx);
+// Count the created sessions in the current minute window, the window key expires with it
+pub async fn count_session_in_redis(redis: &RedisClient) -> Result<u64> {
+    let minute = Utc::now().timestamp() / 60;
+    let key = format!("zpr:sessions:{minute}");
+    let mut conn = redis.get_multiplexed_async_connection().await?;
+    let (count,): (u64,) = redis::pipe()
+        .atomic()
+        .incr(&key, 1)
+        .expire(&key, 60)
+        .ignore()
+        .query_async(&mut conn)
+        .await?;
+    Ok(count)
+}
+
+// The deposited transfer key, by the transaction and the log index of the token transfer
+fn transaction_key(tx: &str, log_index: Option<u64>) -> String {
+    match log_index {
+        Some(index) => format!("zpt:{tx}:{index}"),
+        None => format!("zpt:{tx}"),
+    }
+}
+
+// Store tranaction in Redis for avoid duplicate
+async fn store_transaction_in_redis(redis: &RedisClient, key: &str) -> Result<()> {
+    let mut conn = redis.get_multiplexed_async_connection().await?;
 
     // Set expiration to 1 days enough
-    let _: () = conn.set_ex(&key, 1, 24 * 3600).await?;
+    let _: () = conn.set_ex(key, 1, 24 * 3600).await?;
 
-    debug!("Stored transaction in Redis: {}", tx);
+    debug!("Stored transaction in Redis: {}", key);
     Ok(())
 }
@@ -0,0 +1,78 @@
+use crate::error::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use x402::DiscoveryItem;
+
+#[derive(Serialize, Deserialize)]
+pub struct Resource {
+    pub resource: String,
+    pub resource_type: String,
+    pub x402_version: i32,
+    pub accepts: String,
+    pub metadata: Option<String>,
+    pub last_updated: NaiveDateTime,
+}
+
+impl Resource {
+    pub async fn upsert(item: &DiscoveryItem, db: &PgPool) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let accepts = serde_json::to_string(&item.accepts).unwrap_or_default();
+        let metadata = item.metadata.as_ref().map(|m| m.to_string());
+        query!(
+            "INSERT INTO resources(resource,resource_type,x402_version,accepts,metadata,last_updated) VALUES ($1,$2,$3,$4,$5,$6)
+             ON CONFLICT (resource) DO UPDATE SET resource_type=$2,x402_version=$3,accepts=$4,metadata=$5,last_updated=$6",
+            item.resource,
+            item.r#type,
+            item.x402_version,
+            accepts,
+            metadata,
+            now,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list(
+        resource_type: Option<String>,
+        limit: i64,
+        offset: i64,
+        db: &PgPool,
+    ) -> Result<Vec<Self>> {
+        let res = query_as!(
+            Self,
+            "SELECT * FROM resources WHERE ($1::VARCHAR IS NULL OR resource_type=$1) ORDER BY last_updated DESC LIMIT $2 OFFSET $3",
+            resource_type,
+            limit,
+            offset,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(res)
+    }
+
+    pub async fn count(resource_type: Option<String>, db: &PgPool) -> Result<i64> {
+        let res = query_scalar!(
+            "SELECT COUNT(*) FROM resources WHERE ($1::VARCHAR IS NULL OR resource_type=$1)",
+            resource_type,
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(res.unwrap_or(0))
+    }
+
+    pub fn into_item(self) -> DiscoveryItem {
+        DiscoveryItem {
+            resource: self.resource,
+            r#type: self.resource_type,
+            x402_version: self.x402_version,
+            accepts: serde_json::from_str(&self.accepts).unwrap_or_default(),
+            last_updated: self.last_updated.and_utc().timestamp(),
+            metadata: self.metadata.and_then(|m| serde_json::from_str(&m).ok()),
+        }
+    }
+}
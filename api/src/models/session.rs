@@ -1,8 +1,30 @@
-use crate::error::Result;
-use chrono::{Days, NaiveDateTime, Utc};
+use crate::error::{ApiError, Result};
+use chrono::{Days, NaiveDateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
+/// Max unique amount offset (cents) of the sessions
+const MAX_AMOUNT_OFFSET: i32 = 99;
+
+/// Max tries of the unique session, when a concurrent one reserved the same amount
+const UNIQUE_RETRIES: usize = 3;
+
+/// The unpaid sessions expired after it can still match a deposit, with the grace seconds
+fn matchable_after(now: NaiveDateTime, grace: u64) -> NaiveDateTime {
+    now.checked_sub_signed(TimeDelta::seconds(grace as i64))
+        .unwrap_or(now)
+}
+
+/// The unpaid session expired at the time still matches a deposit now, so its amount is kept
+fn matchable(expired_at: NaiveDateTime, now: NaiveDateTime, grace: u64) -> bool {
+    expired_at > matchable_after(now, grace)
+}
+
+/// The smallest amount offset not used by the matchable sessions amounts
+fn free_offset(amount: i32, used: &[i32]) -> Option<i32> {
+    (1..=MAX_AMOUNT_OFFSET).find(|o| !used.contains(&(amount + o)))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Session {
     pub id: i32,
@@ -12,6 +34,8 @@ pub struct Session {
     pub sent: bool,
     pub updated_at: NaiveDateTime,
     pub expired_at: NaiveDateTime,
+    pub amount_offset: i32,
+    pub offset_reserved: bool,
 }
 
 impl Session {
@@ -31,11 +55,15 @@ impl Session {
         Ok(res)
     }
 
-    pub async fn list_unused(customer: i32, db: &PgPool) -> Result<Vec<Session>> {
+    /// list the unpaid sessions of the customer, which are not expired,
+    /// or expired in the grace seconds, so the late payments can still match them
+    pub async fn list_unused(customer: i32, grace: u64, db: &PgPool) -> Result<Vec<Session>> {
+        let after = matchable_after(Utc::now().naive_utc(), grace);
         let res = query_as!(
             Self,
-            "SELECT * FROM sessions WHERE customer=$1 AND deposit IS NULL ORDER BY id DESC",
+            "SELECT * FROM sessions WHERE customer=$1 AND deposit IS NULL AND expired_at>$2 ORDER BY id DESC",
             customer,
+            after,
         )
         .fetch_all(db)
         .await?;
@@ -61,10 +89,78 @@ impl Session {
         Ok(res)
     }
 
+    /// Insert the session with a unique amount offset added to the amount, no other unpaid
+    /// session of the customer still matchable (unexpired or in the grace seconds) has the same
+    /// amount, so the deposit matches it exactly. The offset is released when the session is
+    /// paid or out of the grace
+    pub async fn insert_unique(
+        customer: i32,
+        amount: i32,
+        grace: u64,
+        db: &PgPool,
+    ) -> Result<Self> {
+        for _ in 0..UNIQUE_RETRIES {
+            let now = Utc::now().naive_utc();
+            let expired_at = now.checked_add_days(Days::new(1)).unwrap_or(now); // 24h
+
+            // the reserved sessions, and the plain unpaid sessions still matchable
+            let sessions = query!(
+                "SELECT id,amount,expired_at,offset_reserved FROM sessions WHERE customer=$1 AND (offset_reserved OR (deposit IS NULL AND expired_at>$2))",
+                customer,
+                matchable_after(now, grace),
+            )
+            .fetch_all(db)
+            .await?;
+            let mut used = vec![];
+            let mut released = vec![];
+            for s in sessions {
+                if matchable(s.expired_at, now, grace) {
+                    used.push(s.amount);
+                } else if s.offset_reserved {
+                    released.push(s.id);
+                }
+            }
+
+            // release the offsets of the sessions out of the grace
+            if !released.is_empty() {
+                let _ = query!(
+                    "UPDATE sessions SET offset_reserved=false WHERE id=ANY($1)",
+                    &released,
+                )
+                .execute(db)
+                .await?;
+            }
+            let offset = free_offset(amount, &used)
+                .ok_or(ApiError::Verify("no available amount offset".to_owned()))?;
+
+            // the unique index rejects the amount reserved by a concurrent session, so try again
+            let res = query_as!(
+                Self,
+                "INSERT INTO sessions(customer,amount,sent,updated_at,expired_at,amount_offset,offset_reserved) VALUES ($1,$2,$3,$4,$5,$6,$7) RETURNING *",
+                customer,
+                amount + offset,
+                false,
+                now,
+                expired_at,
+                offset,
+                true,
+            )
+            .fetch_one(db)
+            .await;
+            match res {
+                Ok(session) => return Ok(session),
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(ApiError::Verify("no available amount offset".to_owned()))
+    }
+
     pub async fn used(&self, deposit: i32, db: &PgPool) -> Result<()> {
         let now = Utc::now().naive_utc();
         let _ = query!(
-            "UPDATE sessions SET deposit=$1, updated_at=$2 WHERE id=$3",
+            "UPDATE sessions SET deposit=$1, updated_at=$2, offset_reserved=false WHERE id=$3",
             deposit,
             now,
             self.id
@@ -83,3 +179,36 @@ impl Session {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_offset() {
+        assert_eq!(free_offset(1000, &[]), Some(1));
+        assert_eq!(free_offset(1000, &[1001, 1002]), Some(3));
+        assert_eq!(free_offset(1000, &[1000, 1003]), Some(1));
+
+        let full: Vec<i32> = (1001..=1000 + MAX_AMOUNT_OFFSET).collect();
+        assert_eq!(free_offset(1000, &full), None);
+    }
+
+    #[test]
+    fn test_grace_session_keeps_offset() {
+        let now = Utc::now().naive_utc();
+        let grace = 3600;
+
+        // unexpired, or expired 10 minutes ago in the grace, still matches a late deposit
+        assert!(matchable(now + TimeDelta::hours(1), now, grace));
+        assert!(matchable(now - TimeDelta::minutes(10), now, grace));
+
+        // out of the grace, the offset is released, the cutoff itself is out
+        assert!(!matchable(now - TimeDelta::hours(2), now, grace));
+        assert!(!matchable(now - TimeDelta::seconds(3600), now, grace));
+
+        // no grace, it is matchable until it expires
+        assert!(matchable(now + TimeDelta::seconds(1), now, 0));
+        assert!(!matchable(now, now, 0));
+    }
+}
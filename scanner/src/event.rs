@@ -4,35 +4,56 @@ use sha2::Sha256;
 /// main session event for webhook
 pub enum ScannerEvent {
     SessionPaid(i32, String, i32),
+    /// paid after the session expired, in the grace period
+    SessionPaidLate(i32, String, i32),
     SessionSettled(i32, String, i32),
-    UnknowPaid(String, i32),
-    UnknowSettled(String, i32),
+    /// the deposit matches no session, with its asset (e.g. `base:USDC`, `base:NATIVE`),
+    /// a native amount is in 1/100 of the native coin, not in cents
+    UnknowPaid(String, i32, String),
+    UnknowSettled(String, i32, String),
 }
 
 impl ScannerEvent {
-    pub async fn send(self, url: &str, apikey: &str) -> anyhow::Result<()> {
+    /// the event name in the webhook payload
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScannerEvent::SessionPaid(..) => "session.paid",
+            ScannerEvent::SessionPaidLate(..) => "session.paid.late",
+            ScannerEvent::SessionSettled(..) => "session.settled",
+            ScannerEvent::UnknowPaid(..) => "unknow.paid",
+            ScannerEvent::UnknowSettled(..) => "unknow.settled",
+        }
+    }
+
+    /// send the event to webhook, sandbox events are marked with `sandbox: true`,
+    /// the sequence is increasing by the event order, the merchant can order the events by it
+    pub async fn send(
+        self,
+        url: &str,
+        apikey: &str,
+        sandbox: bool,
+        sequence: i64,
+    ) -> anyhow::Result<()> {
         let client = reqwest::Client::new();
 
-        let (event, params): (&str, Vec<serde_json::Value>) = match self {
-            ScannerEvent::SessionPaid(sid, customer, amount) => (
-                "session.paid",
-                vec![sid.into(), customer.into(), amount.into()],
-            ),
-            ScannerEvent::SessionSettled(sid, customer, amount) => (
-                "session.settled",
-                vec![sid.into(), customer.into(), amount.into()],
-            ),
-            ScannerEvent::UnknowPaid(customer, amount) => {
-                ("unknow.paid", vec![customer.into(), amount.into()])
+        let event = self.name();
+        let params: Vec<serde_json::Value> = match self {
+            ScannerEvent::SessionPaid(sid, customer, amount)
+            | ScannerEvent::SessionPaidLate(sid, customer, amount)
+            | ScannerEvent::SessionSettled(sid, customer, amount) => {
+                vec![sid.into(), customer.into(), amount.into()]
             }
-            ScannerEvent::UnknowSettled(customer, amount) => {
-                ("unknow.settled", vec![customer.into(), amount.into()])
+            ScannerEvent::UnknowPaid(customer, amount, asset)
+            | ScannerEvent::UnknowSettled(customer, amount, asset) => {
+                vec![customer.into(), amount.into(), asset.into()]
             }
         };
 
         let payload = serde_json::json!({
             "event": event,
-            "params": params
+            "params": params,
+            "sandbox": sandbox,
+            "sequence": sequence
         });
         let body = serde_json::to_vec(&payload).unwrap_or_default();
 
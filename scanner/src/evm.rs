@@ -1,20 +1,40 @@
-use crate::{Chain, ChainDeposit, ScannerMessage};
+use crate::{
+    Chain, ChainDeposit, GasGuard, GasStrategy, MAX_BLOCKS_PER_SCAN, SCAN_INTERVAL_CATCHUP,
+    SCAN_INTERVAL_ERROR, SCAN_INTERVAL_FATAL, SCAN_INTERVAL_RETRY, ScannerMessage,
+    TRACE_INDEX_BASE, TransferError, commission_fee, gas_cost, settlement_fee,
+};
 use alloy::{
-    network::TransactionBuilder,
+    consensus::Transaction as _,
+    eips::BlockNumberOrTag,
+    network::{
+        Ethereum, EthereumWallet, NetworkWallet, TransactionBuilder, TransactionResponse as _,
+    },
     primitives::{Address, B256, U256},
-    providers::{Provider, ProviderBuilder},
+    providers::{Provider, ProviderBuilder, ext::TraceApi},
     rpc::types::TransactionRequest,
+    rpc::types::trace::parity::{Action, CallType},
     rpc::types::{Filter, Log},
     signers::local::PrivateKeySigner,
     sol,
     sol_types::SolEvent,
-    transports::http::reqwest::Url,
+    transports::{
+        RpcError, TransportError, TransportErrorKind,
+        http::reqwest::{Client, Url},
+    },
 };
 use anyhow::Result;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+};
 use tokio::{
-    sync::mpsc::UnboundedSender,
+    sync::{
+        Mutex,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+    },
     time::{Duration, sleep},
 };
+use x402::{DirectSubmitter, Fees, RECEIPT_TIMEOUT, timed};
 
 sol!(
     #[allow(missing_docs)]
@@ -23,16 +43,117 @@ sol!(
     "ERC20.json"
 );
 
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface EvmWrapper {
+        function deposit() external payable;
+    }
+);
+
+// Kind of the scanning error, decide how to retry
+#[derive(Debug, PartialEq, Eq)]
+enum ScanError {
+    // rate limit, timeout, connection error, retry quickly with backoff
+    Transient,
+    // invalid filter, unsupported method, will not recover by retry
+    Fatal,
+    // unknown error
+    Other,
+}
+
+impl ScanError {
+    fn classify(err: &anyhow::Error) -> ScanError {
+        if let Some(e) = err.downcast_ref::<TransportError>() {
+            match e {
+                RpcError::ErrorResp(payload) => match payload.code {
+                    // invalid request, method not found, invalid params
+                    -32602..=-32600 => return ScanError::Fatal,
+                    // rate limit
+                    429 | -32005 => return ScanError::Transient,
+                    _ => {}
+                },
+                RpcError::Transport(TransportErrorKind::HttpError(http))
+                    if http.is_rate_limit_err() || http.is_temporarily_unavailable() =>
+                {
+                    return ScanError::Transient;
+                }
+                RpcError::Transport(TransportErrorKind::BackendGone) => {
+                    return ScanError::Transient;
+                }
+                _ => {}
+            }
+        }
+
+        let msg = format!("{err:?}").to_lowercase();
+        if [
+            "rate limit",
+            "too many requests",
+            "timed out",
+            "timeout",
+            "connection",
+        ]
+        .iter()
+        .any(|m| msg.contains(m))
+        {
+            ScanError::Transient
+        } else if [
+            "method not found",
+            "not supported",
+            "invalid filter",
+            "invalid params",
+        ]
+        .iter()
+        .any(|m| msg.contains(m))
+        {
+            ScanError::Fatal
+        } else {
+            ScanError::Other
+        }
+    }
+}
+
+// Max recently-seen transfer logs kept for in-process dedup
+const SEEN_LOGS_CAPACITY: usize = 10_000;
+
+// Stable scans (no reorg) before the adaptive latency decreases one block
+const LATENCY_STABLE_SCANS: u32 = 100;
+
 // Scanner state to track progress
 #[derive(Debug)]
 pub struct Scanner {
     index: usize,
+    name: String,
     latency: u64,
+    // about one block, the interval of normal and idle scanning
+    block_time: Duration,
+    // adaptive latency range, from the configured latency to max_latency
+    min_latency: u64,
+    max_latency: u64,
+    // block range and the last block hash of the last scan, for reorg detection
+    last_scan: Option<(u64, u64, B256)>,
+    // continuous scans without reorg
+    stable_scans: u32,
     rpc: Url,
+    http: Client,
     tokens: Vec<Address>,
     event: B256,
+    native: bool,
+    native_traces: bool,
+    main: Address,
     last_scanned_block: u64,
     sender: UnboundedSender<ScannerMessage>,
+    control: UnboundedReceiver<ScannerMessage>,
+    // recently-seen (tx, log_index), the same log maybe emitted twice
+    // when rescanning, before the transaction saved by storage
+    seen: HashSet<(B256, u64)>,
+    seen_order: VecDeque<(B256, u64)>,
+    // an admin rescan sends the seen logs again, the storage dedupes them
+    rescanning: bool,
+    // continuous transient errors, for retry backoff
+    retries: u32,
+    // paused by operator, skip scanning
+    paused: bool,
 }
 
 impl Scanner {
@@ -40,36 +161,111 @@ impl Scanner {
         index: usize,
         chain: &Chain,
         sender: UnboundedSender<ScannerMessage>,
+        control: UnboundedReceiver<ScannerMessage>,
     ) -> Result<Self> {
         let event = EvmToken::Transfer::SIGNATURE_HASH;
 
         let mut scan = Self {
             index,
+            name: chain.chain_name.clone(),
             latency: chain.latency as u64,
+            block_time: chain.block_time,
+            min_latency: chain.latency as u64,
+            max_latency: chain.max_latency as u64,
+            last_scan: None,
+            stable_scans: 0,
             rpc: chain.rpc.clone(),
+            http: chain.http.clone(),
             tokens: chain.assets.keys().copied().collect(),
             event,
+            native: chain.native.is_some(),
+            native_traces: chain.native_traces,
+            main: chain.wallet.address(),
             last_scanned_block: chain.last_scanned_block as u64,
             sender,
+            control,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            rescanning: false,
+            retries: 0,
+            paused: false,
         };
 
         if scan.last_scanned_block == 0 {
             scan.last_scanned_block = scan.get_latest_block().await?;
         }
+        scan.record_latency();
 
         Ok(scan)
     }
 
+    // Export the current effective latency
+    fn record_latency(&self) {
+        metrics::gauge!("zeropay_scanner_latency_blocks", "chain" => self.name.clone())
+            .set(self.latency as f64);
+    }
+
+    // Get the block hash, None if the block not exists
+    async fn get_block_hash(&self, number: u64) -> Result<Option<B256>> {
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
+        let block = timed("get_block", provider.get_block_by_number(number.into())).await?;
+        Ok(block.map(|b| b.header.hash))
+    }
+
+    // Check the last scanned block is still canonical. If not, a reorg deeper than the latency
+    // happened, so double the latency and rescan the last range. It decreases one block after
+    // LATENCY_STABLE_SCANS scans without reorg, and always within min_latency..=max_latency
+    async fn check_reorg(&mut self) -> Result<()> {
+        let Some((from_block, to_block, hash)) = self.last_scan else {
+            return Ok(());
+        };
+
+        if self.get_block_hash(to_block).await? == Some(hash) {
+            self.stable_scans += 1;
+            if self.stable_scans >= LATENCY_STABLE_SCANS && self.latency > self.min_latency {
+                self.stable_scans = 0;
+                self.latency -= 1;
+                self.record_latency();
+                tracing::info!(
+                    "Chain {}: Latency decreased to {}",
+                    self.index,
+                    self.latency
+                );
+            }
+            return Ok(());
+        }
+
+        self.stable_scans = 0;
+        self.latency = (self.latency * 2)
+            .max(self.latency + 1)
+            .min(self.max_latency);
+        self.record_latency();
+        tracing::warn!(
+            "Chain {}: Reorg detected at block {}, latency increased to {}",
+            self.index,
+            to_block,
+            self.latency
+        );
+
+        // the logs of the new canonical blocks, the handled deposits are skipped
+        self.scan_range(from_block, to_block).await?;
+        self.last_scan = self
+            .get_block_hash(to_block)
+            .await?
+            .map(|hash| (from_block, to_block, hash));
+        Ok(())
+    }
+
     // Get the latest block number from the chain
     async fn get_latest_block(&self) -> Result<u64> {
-        let provider = ProviderBuilder::new().connect_http(self.rpc.clone());
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
         let block_number = provider.get_block_number().await?;
         Ok(block_number)
     }
 
     // Scan for transfer events in a block range
-    async fn scan_range(&self, from_block: u64, to_block: u64) -> Result<()> {
-        let provider = ProviderBuilder::new().connect_http(self.rpc.clone());
+    async fn scan_range(&mut self, from_block: u64, to_block: u64) -> Result<()> {
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
 
         // Create filter for Transfer events from our monitored tokens
         let filter = Filter::new()
@@ -85,15 +281,133 @@ impl Scanner {
             }
         }
 
+        if self.native {
+            if self.native_traces {
+                self.scan_native_traces(&provider, from_block, to_block)
+                    .await?;
+            } else {
+                self.scan_native(&provider, from_block, to_block).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Scan native value transfers in the blocks, it fetches every full block,
+    // so it is much heavier than the logs filter
+    async fn scan_native(
+        &self,
+        provider: &impl Provider,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<()> {
+        for number in from_block..=to_block {
+            let Some(block) = timed(
+                "get_block",
+                provider.get_block_by_number(number.into()).full(),
+            )
+            .await?
+            else {
+                continue;
+            };
+
+            for tx in block.transactions.into_transactions() {
+                // skip the gas top-up which sent by admin account when settlement
+                let value = tx.value();
+                if value.is_zero() || tx.from() == self.main {
+                    continue;
+                }
+                if let Some(to) = tx.to() {
+                    let _ = self.sender.send(ScannerMessage::Deposit(
+                        self.index,
+                        ChainDeposit::EvmNative(to, value, tx.tx_hash(), None),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Scan native value transfers by the parity `trace_block`, it also finds the internal
+    // transfers of contracts (e.g. a payment routed by a contract), but needs the trace API
+    async fn scan_native_traces(
+        &self,
+        provider: &impl Provider,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<()> {
+        for number in from_block..=to_block {
+            let traces = timed("trace_block", provider.trace_block(number.into())).await?;
+            let mut last_tx = None;
+            let mut position = 0;
+            for trace in traces {
+                // the position of the call in its transaction, the top-level call is 0
+                if trace.transaction_hash == last_tx {
+                    position += 1;
+                } else {
+                    last_tx = trace.transaction_hash;
+                    position = 0;
+                }
+
+                // skip the failed calls, which transferred nothing
+                if trace.trace.error.is_some() {
+                    continue;
+                }
+                let Action::Call(call) = trace.trace.action else {
+                    continue;
+                };
+                // delegatecall and staticcall carry no value of their own,
+                // and skip the gas top-up which sent by admin account when settlement
+                if call.call_type != CallType::Call
+                    || call.value.is_zero()
+                    || call.from == self.main
+                {
+                    continue;
+                }
+                if let Some(tx) = trace.transaction_hash {
+                    // the top-level call has no index, the same as the full blocks scan,
+                    // every internal call is a deposit of its own
+                    let call_index = (position > 0).then(|| TRACE_INDEX_BASE + position);
+                    let _ = self.sender.send(ScannerMessage::Deposit(
+                        self.index,
+                        ChainDeposit::EvmNative(call.to, call.value, tx, call_index),
+                    ));
+                }
+            }
+        }
+
         Ok(())
     }
 
     // Parse a log into a TransferEvent
-    fn handle_transfer_event(&self, log: Log) -> Result<()> {
+    fn handle_transfer_event(&mut self, log: Log) -> Result<()> {
         // ERC20 Transfer event signature: Transfer(address,address,uint256)
         let event = EvmToken::Transfer::decode_log(&log.inner)?;
         tracing::debug!("Fetch event: {}-{}:{}", event.from, event.to, event.value);
 
+        // skip the self-transfers and the transfers to admin account (e.g. commission),
+        // they are not customer deposits
+        if event.to == event.from || event.to == self.main {
+            tracing::debug!("Skip internal transfer: {}-{}", event.from, event.to);
+            return Ok(());
+        }
+
+        // skip the log had been sent in this run, unless rescanning
+        let tx = log.transaction_hash.unwrap_or(B256::ZERO);
+        let log_index = log.log_index.unwrap_or(0);
+        if self.seen.insert((tx, log_index)) {
+            self.seen_order.push_back((tx, log_index));
+            if self.seen_order.len() > SEEN_LOGS_CAPACITY
+                && let Some(old) = self.seen_order.pop_front()
+            {
+                self.seen.remove(&old);
+            }
+        } else if !self.rescanning {
+            tracing::debug!("Duplicate event: {tx}:{log_index}");
+            return Ok(());
+        }
+
         // Send deposit message for processing
         let _ = self.sender.send(ScannerMessage::Deposit(
             self.index,
@@ -101,17 +415,22 @@ impl Scanner {
                 log.address(), // token address
                 event.to,
                 event.value,
-                log.transaction_hash.unwrap_or(B256::ZERO), // tx hash
+                tx, // tx hash
+                log_index,
             ),
         ));
 
         // block_number: log.block_number.unwrap_or(0),
-        // log_index: log.log_index.unwrap_or(0),
         Ok(())
     }
 
     // Single scan iteration
     async fn scan_iteration(&mut self, max_blocks_per_scan: u64) -> Result<u64> {
+        let adaptive = self.max_latency > self.min_latency;
+        if adaptive {
+            self.check_reorg().await?;
+        }
+
         // IMPORTANT: for better finalized, we slower some-block, works for almost blockchain
         let latest_block = self.get_latest_block().await? - self.latency;
 
@@ -129,18 +448,84 @@ impl Scanner {
 
         let scanned_blocks = to_block - from_block + 1;
         self.last_scanned_block = to_block;
+        if adaptive {
+            self.last_scan = self
+                .get_block_hash(to_block)
+                .await?
+                .map(|hash| (from_block, to_block, hash));
+        }
 
         Ok(scanned_blocks)
     }
 
+    // Re-scan a block range, only scanned blocks, and keep the scanned block not changed.
+    // It is idempotent, the deposits had handled will be skipped by transaction
+    async fn rescan(&mut self, from_block: u64, to_block: u64) -> Result<()> {
+        let to_block = std::cmp::min(to_block, self.last_scanned_block);
+        self.rescanning = true;
+        let mut res = Ok(());
+        let mut from = from_block;
+        while from <= to_block {
+            let to = std::cmp::min(from + MAX_BLOCKS_PER_SCAN, to_block);
+            res = self.scan_range(from, to).await;
+            if res.is_err() {
+                break;
+            }
+            from = to + 1;
+        }
+        self.rescanning = false;
+
+        res
+    }
+
+    // Handle control messages
+    async fn handle_control(&mut self, msg: ScannerMessage) {
+        match msg {
+            ScannerMessage::Pause(_) => {
+                self.paused = true;
+                tracing::warn!("Chain {}: Scanning paused", self.index);
+            }
+            ScannerMessage::Resume(_) => {
+                self.paused = false;
+                tracing::warn!("Chain {}: Scanning resumed", self.index);
+            }
+            ScannerMessage::Rescan(_, from_block, to_block) => {
+                match self.rescan(from_block, to_block).await {
+                    Ok(_) => tracing::info!(
+                        "Chain {}: Rescanned blocks {} - {}",
+                        self.index,
+                        from_block,
+                        to_block
+                    ),
+                    Err(e) => tracing::error!(
+                        "Chain {}: Rescan {} - {} error: {}",
+                        self.index,
+                        from_block,
+                        to_block,
+                        e
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+
     // start scanning loop
     pub fn run(mut self) {
         tokio::spawn(async move {
-            let max_blocks_per_scan = 100u64; // Limit blocks per scan to avoid RPC timeouts
-
             loop {
-                let scan_interval = match self.scan_iteration(max_blocks_per_scan).await {
+                // paused, keep the scanned block and only wait for control messages
+                if self.paused {
+                    match self.control.recv().await {
+                        Some(msg) => self.handle_control(msg).await,
+                        None => break,
+                    }
+                    continue;
+                }
+
+                let scan_interval = match self.scan_iteration(MAX_BLOCKS_PER_SCAN).await {
                     Ok(scanned_blocks) => {
+                        self.retries = 0;
                         if scanned_blocks > 0 {
                             tracing::info!(
                                 "Chain {}: Scanned {} blocks, current block: {}",
@@ -150,139 +535,346 @@ impl Scanner {
                             );
 
                             // If we're catching up, scan faster
-                            if scanned_blocks >= max_blocks_per_scan {
-                                Duration::from_secs(1)
+                            if scanned_blocks >= MAX_BLOCKS_PER_SCAN {
+                                Duration::from_secs(SCAN_INTERVAL_CATCHUP).min(self.block_time)
                             } else {
-                                // Normal scanning interval
-                                Duration::from_secs(10)
+                                // Normal scanning interval, the next block is about one block time
+                                self.block_time
                             }
                         } else {
-                            // No new blocks, increase interval slightly
-                            Duration::from_secs(15)
+                            // No new blocks, wait for the next block
+                            self.block_time
                         }
                     }
-                    Err(e) => {
-                        tracing::error!("Chain {}: Scan error: {}", self.index, e);
-                        // On error, wait longer before retrying
-                        Duration::from_secs(30)
-                    }
+                    Err(e) => match ScanError::classify(&e) {
+                        ScanError::Transient => {
+                            // retry quickly, and backoff when it continues
+                            let retry = (SCAN_INTERVAL_RETRY << self.retries.min(8))
+                                .min(SCAN_INTERVAL_ERROR);
+                            self.retries += 1;
+                            tracing::warn!(
+                                "Chain {}: Scan transient error, retry in {}s: {}",
+                                self.index,
+                                retry,
+                                e
+                            );
+                            Duration::from_secs(retry)
+                        }
+                        ScanError::Fatal => {
+                            tracing::error!("Chain {}: Scan fatal error: {}", self.index, e);
+                            // do not hammer the endpoint, it will not recover soon
+                            Duration::from_secs(SCAN_INTERVAL_FATAL)
+                        }
+                        ScanError::Other => {
+                            tracing::error!("Chain {}: Scan error: {}", self.index, e);
+                            // On error, wait longer before retrying
+                            Duration::from_secs(SCAN_INTERVAL_ERROR)
+                        }
+                    },
                 };
 
-                sleep(scan_interval).await;
+                // wait for next scanning, and handle control messages meanwhile
+                tokio::select! {
+                    _ = sleep(scan_interval) => {}
+                    Some(msg) = self.control.recv() => self.handle_control(msg).await,
+                }
             }
         });
     }
 }
 
+// Conservative gas of settlement: top-up tx (21000) + customer approve + two transferFrom
+const SETTLEMENT_GAS: u64 = 21_000 + 60_000 + 2 * TRANSFER_FROM_GAS;
+
+// Conservative gas of a token transferFrom
+pub(crate) const TRANSFER_FROM_GAS: u64 = 65_000;
+
 // transfer token from deposit to admin, return real merchant amount
 #[allow(clippy::too_many_arguments)]
 pub async fn transfer(
     customer: Address,
     merchant: Address,
     token: Address,
-    wallet: PrivateKeySigner,
+    wallet: EthereumWallet,
     main: PrivateKeySigner,
+    http: Client,
     url: Url,
-    commission_rate: i32,
+    commission_bps: i32,
     commission_min: U256,
     commission_max: U256,
+    gas_check: bool,
+    gas: GasStrategy,
+    max_gas_price: Option<u128>,
+    gas_guard: Option<(GasGuard, U256)>,
+    nonce_lock: Arc<Mutex<()>>,
 ) -> Result<(U256, B256)> {
     let zero = U256::from(0);
     let maccount = main.address();
     let provider = ProviderBuilder::new()
-        .wallet(main)
-        .connect_http(url.clone());
-    let gas_price = provider.get_gas_price().await? * 105 / 100; // add 5%
+        .wallet(main.clone())
+        .connect_reqwest(http.clone(), url.clone());
+    let gas_price = Fees::fetch_explicit(gas, &provider).await?;
+    if let Some(ceiling) = max_gas_price
+        && gas_price.max() > ceiling
+    {
+        return Err(anyhow::anyhow!(
+            "Gas price {} is above the max gas price {ceiling}",
+            gas_price.max()
+        ));
+    }
+
+    // 0. check admin wallet can pay all the gas: approve gas top-up and two transfers
+    if gas_check {
+        let required = U256::from(gas_price.max()) * U256::from(SETTLEMENT_GAS);
+        let native = timed("get_balance", provider.get_balance(maccount)).await?;
+        if native < required {
+            return Err(TransferError::InsufficientGasFunds(native, required).into());
+        }
+    }
+
     let contract = EvmToken::new(token, provider.clone());
 
     // 1. check token balance
-    let balance: U256 = contract.balanceOf(customer).call().await?;
+    let balance: U256 = timed("balance_of", contract.balanceOf(customer).call()).await?;
 
     if balance == zero {
         return Err(anyhow::anyhow!("No balance"));
     }
 
     // 3. check approve or not
-    let approved: U256 = contract.allowance(customer, maccount).call().await?;
+    let approved: U256 = timed("allowance", contract.allowance(customer, maccount).call()).await?;
     let need_approve = approved < balance;
 
     // 2. collect gas used, and do a discount in the amount
     let approve_gas = if need_approve {
-        let gas = contract
+        let mut tx = contract
             .approve(maccount, U256::from(100_000_000_000_000i64))
-            .gas_price(gas_price)
-            .estimate_gas()
-            .await?;
+            .into_transaction_request();
+        gas_price.apply(&mut tx);
+        let gas = timed("estimate_gas", provider.estimate_gas(tx)).await?;
         // add more 5%
-        U256::from(gas * 105 / 100) * U256::from(gas_price)
+        U256::from(gas * 105 / 100) * U256::from(gas_price.max())
     } else {
         zero
     };
     tracing::debug!("{customer}: approve_gas: {approve_gas}");
 
-    let fee = if commission_rate > 0 {
-        let rate = balance * U256::from(commission_rate) / U256::from(100);
-        let rate_max = core::cmp::min(rate, commission_max);
-        core::cmp::max(rate_max, commission_min)
-    } else {
-        zero
-    };
+    // the commission must cover the admin gas of the settlement, the guard has the token amount
+    // of one native coin, and the two transferFrom are counted even without commission
+    let commission = commission_fee(balance, commission_bps, commission_min, commission_max);
+    let guard = gas_guard.map(|(guard, native_rate)| {
+        let gas = 2 * TRANSFER_FROM_GAS + if need_approve { 21_000 } else { 0 };
+        let cost = gas_cost(gas, gas_price.max(), approve_gas, native_rate);
+        (guard, cost)
+    });
+    let fee = settlement_fee(commission, balance, guard)?;
     let real = balance - fee;
     tracing::info!("{customer}: commission: {fee}, real: {real}");
 
+    // the admin transactions of the chain are sent one by one by the shared nonce lock,
+    // an underpriced or stuck one is replaced with a bumped price on its nonce
+    let submitter = DirectSubmitter::new(url.clone(), http.clone(), main)
+        .gas(gas)
+        .max_gas_price(max_gas_price)
+        .nonce_lock(nonce_lock);
     if need_approve {
-        // 4. if not approve, transfer approve gas to it
-        let ttx = TransactionRequest::default()
+        // 4. if not approve, transfer approve gas to it, the approve is sent at the price of
+        // the approve gas, even if the top-up is bumped
+        let topup = TransactionRequest::default()
             .with_to(customer)
             .with_value(approve_gas);
-        let pending = provider.send_transaction(ttx).await?;
-        tracing::debug!("{customer}: approve gas sent");
-        let _receipt = pending.get_receipt().await?;
+        let _ = submitter.send(topup).await?;
         tracing::debug!("{customer}: approve gas arrived");
 
         // 5. approve tokens to max
-        let customer_provider = ProviderBuilder::new().wallet(wallet).connect_http(url);
+        let customer_provider = ProviderBuilder::new()
+            .wallet(wallet)
+            .connect_reqwest(http, url);
         let customer_contract = EvmToken::new(token, customer_provider);
-        let total = customer_contract
-            .totalSupply()
-            .call()
+        let total = timed("total_supply", customer_contract.totalSupply().call())
             .await
             .unwrap_or(U256::from(100_000_000_000_000i64));
 
-        let pending = customer_contract
+        let mut tx = customer_contract
             .approve(maccount, total)
-            .gas_price(gas_price)
-            .send()
-            .await?;
+            .into_transaction_request();
+        gas_price.apply(&mut tx);
+        let pending = timed(
+            "send_transaction",
+            customer_contract.provider().send_transaction(tx),
+        )
+        .await?;
         tracing::debug!("{customer}: approved sent");
-        let _receipt = pending.get_receipt().await?;
+        let receipt = tokio::time::timeout(
+            Duration::from_secs(RECEIPT_TIMEOUT),
+            timed("get_receipt", pending.get_receipt()),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Approve is not confirmed in {RECEIPT_TIMEOUT}s"))??;
+        if !receipt.status() {
+            return Err(anyhow::anyhow!(
+                "Approve {} reverted",
+                receipt.transaction_hash
+            ));
+        }
         tracing::debug!("{customer}: approved arrived");
     }
 
     // 6. transfer remain token to merchant
-    let pending = contract
-        .transferFrom(customer, merchant, real)
-        .gas_price(gas_price)
-        .send()
-        .await?;
-    tracing::debug!("{customer}: transfer real sent");
-    let receipt = pending.get_receipt().await?;
+    let real_tx = contract.transferFrom(customer, merchant, real);
+    let tx_hash = submitter.send(real_tx.into_transaction_request()).await?;
     tracing::debug!("{customer}: transfer real arrived");
 
     if fee > zero {
-        let pending2 = contract
-            .transferFrom(customer, maccount, fee)
-            .gas_price(gas_price)
-            .send()
-            .await?;
-        tracing::debug!("{customer}: transfer commission sent");
-        let _ = pending2.get_receipt().await?;
+        let fee_tx = contract.transferFrom(customer, maccount, fee);
+        let _ = submitter.send(fee_tx.into_transaction_request()).await?;
         tracing::debug!("{customer}: transfer commission arrived");
     }
 
+    Ok((real, tx_hash))
+}
+
+// transfer native currency from deposit to merchant, the gas is paid by the deposit,
+// return real merchant amount
+pub async fn sweep(
+    wallet: EthereumWallet,
+    merchant: Address,
+    main: Address,
+    http: Client,
+    url: Url,
+    commission_bps: i32,
+    gas: GasStrategy,
+) -> Result<(U256, B256)> {
+    let customer = NetworkWallet::<Ethereum>::default_signer_address(&wallet);
+    let provider = ProviderBuilder::new()
+        .wallet(wallet)
+        .connect_reqwest(http, url);
+    let gas_price = Fees::fetch_explicit(gas, &provider).await?;
+    let balance = timed("get_balance", provider.get_balance(customer)).await?;
+
+    // 1. merchant maybe a contract wallet, so estimate the receive gas
+    let test = TransactionRequest::default()
+        .with_from(customer)
+        .with_to(merchant)
+        .with_value(U256::from(1));
+    let gas = timed("estimate_gas", provider.estimate_gas(test)).await? * 105 / 100;
+
+    let fee = if commission_bps > 0 {
+        balance * U256::from(commission_bps) / U256::from(10_000)
+    } else {
+        U256::ZERO
+    };
+    let txs = if fee > U256::ZERO { 2 } else { 1 };
+    let cost = U256::from(gas_price.max()) * U256::from(gas) * U256::from(txs);
+    if balance <= fee + cost {
+        return Err(anyhow::anyhow!("Balance can not pay the gas"));
+    }
+    let real = balance - fee - cost;
+    tracing::info!("{customer}: native commission: {fee}, gas: {cost}, real: {real}");
+
+    // 2. transfer remain native to merchant
+    let mut ttx = TransactionRequest::default()
+        .with_to(merchant)
+        .with_value(real)
+        .with_gas_limit(gas);
+    gas_price.apply(&mut ttx);
+    let pending = timed("send_transaction", provider.send_transaction(ttx)).await?;
+    tracing::debug!("{customer}: sweep real sent");
+    let receipt = timed("get_receipt", pending.get_receipt()).await?;
+    tracing::debug!("{customer}: sweep real arrived");
+
+    if fee > U256::ZERO {
+        let mut ftx = TransactionRequest::default()
+            .with_to(main)
+            .with_value(fee)
+            .with_gas_limit(21_000);
+        gas_price.apply(&mut ftx);
+        let pending2 = timed("send_transaction", provider.send_transaction(ftx)).await?;
+        tracing::debug!("{customer}: sweep commission sent");
+        let _ = timed("get_receipt", pending2.get_receipt()).await?;
+        tracing::debug!("{customer}: sweep commission arrived");
+    }
+
     Ok((real, receipt.transaction_hash))
 }
 
+// wrap all native currency of deposit to the wrapper token (e.g. WETH),
+// the gas is paid by the deposit, return the wrapped amount
+pub async fn wrap(
+    wallet: EthereumWallet,
+    wrapper: Address,
+    http: Client,
+    url: Url,
+    gas: GasStrategy,
+) -> Result<U256> {
+    let customer = NetworkWallet::<Ethereum>::default_signer_address(&wallet);
+    let provider = ProviderBuilder::new()
+        .wallet(wallet)
+        .connect_reqwest(http, url);
+    let gas_price = Fees::fetch_explicit(gas, &provider).await?;
+    let balance = timed("get_balance", provider.get_balance(customer)).await?;
+
+    let contract = EvmWrapper::new(wrapper, provider);
+    let gas = timed(
+        "estimate_gas",
+        contract.deposit().value(U256::from(1)).estimate_gas(),
+    )
+    .await?
+        * 105
+        / 100;
+    let cost = U256::from(gas_price.max()) * U256::from(gas);
+    if balance <= cost {
+        return Err(anyhow::anyhow!("Balance can not pay the gas"));
+    }
+    let amount = balance - cost;
+
+    let mut tx = contract
+        .deposit()
+        .value(amount)
+        .gas(gas)
+        .into_transaction_request();
+    gas_price.apply(&mut tx);
+    let pending = timed("send_transaction", contract.provider().send_transaction(tx)).await?;
+    tracing::debug!("{customer}: wrap sent");
+    let _receipt = timed("get_receipt", pending.get_receipt()).await?;
+    tracing::debug!("{customer}: wrap arrived {amount}");
+
+    Ok(amount)
+}
+
+/// Measure the average block time in milliseconds by the timestamps of the latest `span` blocks,
+/// the timestamps are in seconds, so the span makes the sub-second block time measurable
+pub async fn measure_block_time(provider: &impl Provider, span: u64) -> Result<u64> {
+    let latest = timed(
+        "get_block",
+        provider.get_block_by_number(BlockNumberOrTag::Latest),
+    )
+    .await?
+    .ok_or(anyhow::anyhow!("No latest block"))?;
+    let span = span.min(latest.header.number).max(1);
+    let earlier = timed(
+        "get_block",
+        provider.get_block_by_number((latest.header.number - span).into()),
+    )
+    .await?
+    .ok_or(anyhow::anyhow!("No block {}", latest.header.number - span))?;
+
+    let elapsed = latest
+        .header
+        .timestamp
+        .saturating_sub(earlier.header.timestamp);
+    if elapsed == 0 {
+        return Err(anyhow::anyhow!("Same timestamps of {span} blocks"));
+    }
+    Ok(elapsed * 1000 / span)
+}
+
+/// Check the address has code, e.g. a multisig or smart account
+pub async fn is_contract(addr: Address, provider: impl Provider) -> Result<bool> {
+    let code = timed("get_code", provider.get_code_at(addr)).await?;
+    Ok(!code.is_empty())
+}
+
 pub async fn get_token_decimal(token: Address, provider: impl Provider) -> Result<u8> {
     let contract = EvmToken::new(token, provider);
     Ok(contract.decimals().call().await?)
@@ -305,3 +897,136 @@ pub fn i32_to_u256(amount: i32, decimal: &u8) -> U256 {
         U256::from(amount) / U256::from(10).pow(U256::from(2 - *decimal))
     }
 }
+
+/// The current gas price (wei) of the strategy, the max fee of EIP-1559
+pub(crate) async fn current_gas_price(
+    strategy: GasStrategy,
+    provider: &impl Provider,
+) -> Result<u128> {
+    Ok(Fees::fetch_explicit(strategy, provider).await?.max())
+}
+
+/// Convert a token amount of float (e.g. the native rate) to U256, with 6 decimals precision
+pub fn rate_to_u256(rate: f64, decimal: &u8) -> U256 {
+    let micros = U256::from((rate * 1_000_000.0).round().max(0.0) as u128);
+    micros * U256::from(10).pow(U256::from(*decimal)) / U256::from(1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const MAIN: Address = Address::repeat_byte(0xaa);
+    const TOKEN: Address = Address::repeat_byte(0x01);
+
+    fn scanner() -> (Scanner, UnboundedReceiver<ScannerMessage>) {
+        let (sender, receiver) = unbounded_channel();
+        let (_control_sender, control) = unbounded_channel();
+        let scanner = Scanner {
+            index: 0,
+            name: "test".to_owned(),
+            latency: 0,
+            block_time: Duration::from_secs(1),
+            min_latency: 0,
+            max_latency: 0,
+            last_scan: None,
+            stable_scans: 0,
+            rpc: "http://localhost:8545".parse().unwrap(),
+            http: Client::new(),
+            tokens: vec![TOKEN],
+            event: EvmToken::Transfer::SIGNATURE_HASH,
+            native: false,
+            native_traces: false,
+            main: MAIN,
+            last_scanned_block: 0,
+            sender,
+            control,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            rescanning: false,
+            retries: 0,
+            paused: false,
+        };
+        (scanner, receiver)
+    }
+
+    fn transfer(from: Address, to: Address, log_index: u64) -> Log {
+        let event = EvmToken::Transfer {
+            from,
+            to,
+            value: U256::from(1_000_000),
+        };
+        Log {
+            inner: alloy::primitives::Log {
+                address: TOKEN,
+                data: event.encode_log_data(),
+            },
+            transaction_hash: Some(B256::repeat_byte(0x11)),
+            log_index: Some(log_index),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rate_to_u256() {
+        assert_eq!(rate_to_u256(2500.0, &6), U256::from(2_500_000_000u64));
+        assert_eq!(
+            rate_to_u256(0.035, &8),
+            U256::from(3_500_000u64) // 0.035 WBTC of 8 decimals
+        );
+        assert_eq!(
+            rate_to_u256(1.5, &18),
+            U256::from(15) * U256::from(10).pow(U256::from(17))
+        );
+    }
+
+    #[test]
+    fn test_skip_internal_transfers() {
+        let (mut scanner, mut receiver) = scanner();
+        let customer = Address::repeat_byte(0x02);
+
+        // self-transfer, and the commission to the admin account
+        scanner
+            .handle_transfer_event(transfer(customer, customer, 0))
+            .unwrap();
+        scanner
+            .handle_transfer_event(transfer(customer, MAIN, 1))
+            .unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        // the customer deposit, once per log
+        scanner
+            .handle_transfer_event(transfer(Address::repeat_byte(0x03), customer, 2))
+            .unwrap();
+        scanner
+            .handle_transfer_event(transfer(Address::repeat_byte(0x03), customer, 2))
+            .unwrap();
+        match receiver.try_recv() {
+            Ok(ScannerMessage::Deposit(0, ChainDeposit::Evm(token, to, _, _, 2))) => {
+                assert_eq!(token, TOKEN);
+                assert_eq!(to, customer);
+            }
+            _ => panic!("expected the deposit"),
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rescan_sends_seen_logs() {
+        let (mut scanner, mut receiver) = scanner();
+        let log = transfer(Address::repeat_byte(0x03), Address::repeat_byte(0x02), 0);
+
+        scanner.handle_transfer_event(log.clone()).unwrap();
+        assert!(receiver.try_recv().is_ok());
+
+        // the rescan sends it again, the storage dedupes it
+        scanner.rescanning = true;
+        scanner.handle_transfer_event(log.clone()).unwrap();
+        assert!(receiver.try_recv().is_ok());
+
+        scanner.rescanning = false;
+        scanner.handle_transfer_event(log).unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+}
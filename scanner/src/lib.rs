@@ -1,40 +1,281 @@
 mod did;
 mod event;
 mod evm;
+mod signer;
 
 pub use did::generate_eth;
 pub use event::ScannerEvent;
+pub use signer::{CustomerSigner, MnemonicSigner, RemoteSigner};
 
 use alloy::{
+    eips::BlockNumberOrTag,
     primitives::{Address, B256, U256},
     providers::{Provider, ProviderBuilder},
     signers::local::PrivateKeySigner,
-    transports::http::reqwest::Url,
+    transports::http::reqwest::{Client, Url},
 };
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+    task::JoinSet,
+};
+
+/// Default timeout (seconds) of every rpc request
+pub const DEFAULT_RPC_TIMEOUT: u64 = 30;
+
+/// Limit blocks per scan to avoid RPC timeouts
+pub const MAX_BLOCKS_PER_SCAN: u64 = 100;
+
+/// Scanning interval (seconds) when catching up
+pub const SCAN_INTERVAL_CATCHUP: u64 = 1;
+
+/// Normal scanning interval (seconds), when the block time is not configured and failed to measure
+pub const SCAN_INTERVAL_NORMAL: u64 = 10;
+
+/// Blocks to measure the average block time, when block_time_ms not configured
+const BLOCK_TIME_SPAN: u64 = 10;
+
+/// Scanning interval (seconds) after a scan error
+pub const SCAN_INTERVAL_ERROR: u64 = 30;
+
+/// First retry interval (seconds) after a transient scan error (rate limit, timeout),
+/// doubled for every retry, and at most SCAN_INTERVAL_ERROR
+pub const SCAN_INTERVAL_RETRY: u64 = 2;
+
+/// Scanning interval (seconds) after a fatal scan error (invalid filter, unsupported method)
+pub const SCAN_INTERVAL_FATAL: u64 = 300;
+
+/// Default max tokens of a chain, bound the startup probing and the logs filter
+pub const DEFAULT_MAX_TOKENS: usize = 50;
+
+/// Max commission in basis points (100%)
+pub const MAX_COMMISSION_BPS: i32 = 10_000;
+
+/// Asset name of the native currency deposits, e.g. `ethereum:NATIVE`
+pub const NATIVE_ASSET: &str = "NATIVE";
+
+/// The index base of the internal native calls in a transaction, after any log index,
+/// so they never collide with the token transfers of the same transaction
+pub const TRACE_INDEX_BASE: u64 = 1 << 32;
+
+/// Decimal of the EVM native currency
+const NATIVE_DECIMAL: u8 = 18;
 
 /// Chain configure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ScannerConfig {
     pub chains: Vec<ChainConfig>,
 }
 
 /// Chain configure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChainConfig {
     pub chain_type: String,
     pub chain_name: String,
     pub latency: i32,
+    /// adapt the latency up to max_latency when a deeper reorg detected, disabled if not set
+    pub max_latency: Option<i32>,
+    /// block time in milliseconds, the scanning interval is about one block, measured by the latest blocks if not set
+    pub block_time_ms: Option<u64>,
     pub estimation: i32,
-    pub commission: i32,
+    /// deprecated, commission rate in whole percent, use commission_bps
+    pub commission: Option<i32>,
+    /// commission rate in basis points (1 bps = 0.01%)
+    pub commission_bps: Option<i32>,
     pub commission_min: i32,
     pub commission_max: i32,
     pub rpc: String,
+    pub timeout: Option<u64>,
+    pub gas_check: Option<bool>,
+    /// gas pricing of the transactions: auto, legacy or eip1559
+    pub gas_strategy: Option<String>,
+    /// EIP-1559 priority fee (wei), default is the node suggested
+    pub gas_tip: Option<u64>,
+    /// the ceiling of the gas price (wei) when retrying the underpriced transactions, default unlimited
+    pub max_gas_price: Option<u64>,
+    /// the token amount of one native coin by the token name (e.g. USDC = 2500.0),
+    /// converts the settlement gas to the token amount
+    pub native_rates: Option<HashMap<String, f64>>,
+    /// when the commission can not cover the settlement gas: skip (hold the deposit)
+    /// or raise (the commission to the gas cost), disabled if not set
+    pub gas_guard: Option<String>,
+    /// trust the configured x402 tokens, skip the EIP-3009 capability probe
+    pub skip_probe: Option<bool>,
+    pub native: Option<String>,
+    /// detect native deposits by `trace_block`, including the internal transfers of contracts
+    pub native_traces: Option<bool>,
     pub admin: Option<String>,
     pub tokens: Vec<String>,
+    pub max_tokens: Option<usize>,
+}
+
+impl ChainConfig {
+    /// Commission rate in basis points, the deprecated percent commission is migrated by multiplying by 100
+    pub fn commission_in_bps(&self) -> i32 {
+        self.commission_bps
+            .unwrap_or(self.commission.unwrap_or(0) * 100)
+    }
+
+    /// Commission of the token amount (2-decimal), the same as the settlement
+    pub fn commission_of(&self, amount: i32) -> i32 {
+        let fee = commission_fee(
+            U256::from(amount.max(0)),
+            self.commission_in_bps(),
+            U256::from(self.commission_min.max(0)),
+            U256::from(self.commission_max.max(0)),
+        );
+        fee.try_into().unwrap_or(i32::MAX)
+    }
+
+    /// The gas guard of the token settlements, disabled if not set
+    pub fn gas_guard(&self) -> Result<Option<GasGuard>> {
+        match self.gas_guard.as_deref() {
+            None => Ok(None),
+            Some("skip") => Ok(Some(GasGuard::Skip)),
+            Some("raise") => Ok(Some(GasGuard::Raise)),
+            Some(_) => Err(anyhow::anyhow!(
+                "{}: gas_guard must be skip or raise",
+                self.chain_name
+            )),
+        }
+    }
+
+    /// Estimate the settlement of the token amount (2-decimal) at the current gas price,
+    /// the same as the settlement of a customer which approved already
+    pub async fn estimate(&self, token: &str, amount: i32) -> Result<SettlementEstimate> {
+        let amount = U256::from(amount.max(0));
+        let commission = commission_fee(
+            amount,
+            self.commission_in_bps(),
+            U256::from(self.commission_min.max(0)),
+            U256::from(self.commission_max.max(0)),
+        );
+        let to_i32 = |v: U256| -> i32 { v.try_into().unwrap_or(i32::MAX) };
+
+        let guard = match self.gas_guard()? {
+            None => None,
+            Some(guard) => match self.native_rates.as_ref().and_then(|r| r.get(token)) {
+                Some(rate) => {
+                    let rpc: Url = self.rpc.parse()?;
+                    let http = Client::builder()
+                        .timeout(Duration::from_secs(
+                            self.timeout.unwrap_or(DEFAULT_RPC_TIMEOUT),
+                        ))
+                        .build()?;
+                    let provider = ProviderBuilder::new().connect_reqwest(http, rpc);
+                    let strategy = gas_strategy(self, &provider).await?;
+                    let gas_price = evm::current_gas_price(strategy, &provider).await?;
+                    let cost = gas_cost(
+                        2 * evm::TRANSFER_FROM_GAS,
+                        gas_price,
+                        U256::ZERO,
+                        evm::rate_to_u256(*rate, &2),
+                    );
+                    Some((guard, cost))
+                }
+                // fail closed, the same as the settlement
+                None => {
+                    return Ok(SettlementEstimate {
+                        commission: to_i32(commission),
+                        gas: 0,
+                        fee: to_i32(commission),
+                        held: true,
+                    });
+                }
+            },
+        };
+
+        let gas = guard.map(|(_, cost)| cost).unwrap_or_default();
+        let (fee, held) = match settlement_fee(commission, amount, guard) {
+            Ok(fee) => (fee, false),
+            Err(_) => (commission, true),
+        };
+        Ok(SettlementEstimate {
+            commission: to_i32(commission),
+            gas: to_i32(gas),
+            fee: to_i32(fee),
+            held,
+        })
+    }
+}
+
+/// The estimated settlement of a token amount, all in the token amount (2-decimal)
+#[derive(Clone, Debug)]
+pub struct SettlementEstimate {
+    /// the commission by the commission rate
+    pub commission: i32,
+    /// the settlement gas cost, 0 without the gas guard
+    pub gas: i32,
+    /// the commission charged, raised to the gas cost by the gas guard
+    pub fee: i32,
+    /// the deposit will be held by the gas guard, not settled
+    pub held: bool,
+}
+
+/// Commission of the settled amount: commission_bps of it, clamped by commission_max,
+/// then at least commission_min. No commission when commission_bps is 0
+pub fn commission_fee(amount: U256, commission_bps: i32, min: U256, max: U256) -> U256 {
+    if commission_bps > 0 {
+        let rate = amount * U256::from(commission_bps) / U256::from(MAX_COMMISSION_BPS);
+        let rate_max = core::cmp::min(rate, max);
+        core::cmp::max(rate_max, min)
+    } else {
+        U256::ZERO
+    }
+}
+
+/// The token amount of the settlement gas: the gas units at the gas price plus the extra native
+/// (wei), by the token amount of one native coin
+pub fn gas_cost(gas: u64, gas_price: u128, extra: U256, native_rate: U256) -> U256 {
+    (U256::from(gas) * U256::from(gas_price) + extra) * native_rate
+        / U256::from(10).pow(U256::from(NATIVE_DECIMAL))
+}
+
+/// The commission charged of the settled amount. With the gas guard and the gas cost (in the
+/// token amount), a commission below the gas cost is raised to it (`Raise`, if the amount can
+/// pay it), otherwise the settlement is rejected as `NetNegative`
+pub fn settlement_fee(
+    commission: U256,
+    amount: U256,
+    guard: Option<(GasGuard, U256)>,
+) -> std::result::Result<U256, TransferError> {
+    match guard {
+        Some((guard, cost)) if commission < cost => match guard {
+            GasGuard::Raise if cost < amount => Ok(cost),
+            _ => Err(TransferError::NetNegative(commission, cost)),
+        },
+        _ => Ok(commission),
+    }
+}
+
+// The gas pricing strategy of the chain, auto detect the EIP-1559 support by the latest block
+async fn gas_strategy(config: &ChainConfig, provider: &impl Provider) -> Result<GasStrategy> {
+    let tip = config.gas_tip.map(u128::from);
+    match config.gas_strategy.as_deref() {
+        None | Some("auto") => {
+            let latest = provider
+                .get_block_by_number(BlockNumberOrTag::Latest)
+                .await?
+                .ok_or(anyhow::anyhow!("{}: no latest block", config.chain_name))?;
+            if latest.header.base_fee_per_gas.is_some() {
+                Ok(GasStrategy::Eip1559(tip))
+            } else {
+                Ok(GasStrategy::Legacy)
+            }
+        }
+        Some("legacy") => Ok(GasStrategy::Legacy),
+        Some("eip1559") => Ok(GasStrategy::Eip1559(tip)),
+        Some(strategy) => Err(anyhow::anyhow!(
+            "{}: unknown gas_strategy {}",
+            config.chain_name,
+            strategy
+        )),
+    }
 }
 
 /// Main storage interface for Scanner used
@@ -45,7 +286,13 @@ pub trait ScannerStorage: Send + Sync + 'static {
         &self,
         address: &str,
     ) -> impl Future<Output = Result<(i32, i32, String)>> + Send;
-    fn no_transaction(&self, tx: &str) -> impl Future<Output = Result<()>> + Send;
+    /// The deposit is by the transaction and the log index of the token transfer,
+    /// a native transfer has no log index, unless it is an internal call of the transaction
+    fn no_transaction(
+        &self,
+        tx: &str,
+        log_index: Option<u64>,
+    ) -> impl Future<Output = Result<()>> + Send;
     fn deposited(
         &self,
         identity: String,
@@ -53,6 +300,7 @@ pub trait ScannerStorage: Send + Sync + 'static {
         cid: i32,
         amount: i32,
         tx: String,
+        log_index: Option<u64>,
     ) -> impl Future<Output = Result<i32>> + Send;
     fn settled(
         &self,
@@ -61,6 +309,87 @@ pub trait ScannerStorage: Send + Sync + 'static {
         amount: i32,
         tx: String,
     ) -> impl Future<Output = Result<()>> + Send;
+    fn set_status(
+        &self,
+        did: i32,
+        status: SettlementStatus,
+    ) -> impl Future<Output = Result<()>> + Send;
+    /// check the deposit should be held and not settled, e.g. it matches no session
+    fn hold(&self, did: i32) -> impl Future<Output = Result<bool>> + Send;
+    /// the settlement status of the deposit
+    fn status(&self, did: i32) -> impl Future<Output = Result<SettlementStatus>> + Send;
+}
+
+/// Settlement transfer errors which need operator action
+#[derive(Debug)]
+pub enum TransferError {
+    /// admin wallet native balance can not pay the settlement gas (balance, required)
+    InsufficientGasFunds(U256, U256),
+    /// the commission can not cover the settlement gas, in the token amount (commission, gas)
+    NetNegative(U256, U256),
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::InsufficientGasFunds(balance, required) => write!(
+                f,
+                "Insufficient gas funds in admin wallet: balance {balance}, required {required}"
+            ),
+            TransferError::NetNegative(fee, gas) => {
+                write!(f, "Commission {fee} can not cover the settlement gas {gas}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// Settlement progress of a deposit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SettlementStatus {
+    /// deposit is detected and saved, not settled yet
+    Detected,
+    /// settlement transactions are being sent
+    Settling,
+    /// funds transferred to merchant
+    Settled,
+    /// settlement failed, need retry or manual handle
+    Failed,
+    /// settlement is handled manually by operator
+    Manual,
+    /// deposit matches no session and is held, until merchant release it
+    Held,
+}
+
+impl SettlementStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SettlementStatus::Detected => "detected",
+            SettlementStatus::Settling => "settling",
+            SettlementStatus::Settled => "settled",
+            SettlementStatus::Failed => "failed",
+            SettlementStatus::Manual => "manual",
+            SettlementStatus::Held => "held",
+        }
+    }
+}
+
+impl FromStr for SettlementStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SettlementStatus> {
+        match s.to_lowercase().as_str() {
+            "detected" => Ok(SettlementStatus::Detected),
+            "settling" => Ok(SettlementStatus::Settling),
+            "settled" => Ok(SettlementStatus::Settled),
+            "failed" => Ok(SettlementStatus::Failed),
+            "manual" => Ok(SettlementStatus::Manual),
+            "held" => Ok(SettlementStatus::Held),
+            _ => Err(anyhow::anyhow!("unknown settlement status {s}")),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -77,17 +406,54 @@ impl ChainType {
     }
 }
 
+/// Gas pricing strategy of the chain transactions, the same as the x402 settlements
+pub use x402::GasStrategy;
+
+/// How to settle the native currency sent to customer addresses
+#[derive(Clone, Copy, Debug)]
+enum NativeSettlement {
+    /// transfer the native currency to merchant directly
+    Sweep,
+    /// wrap to the wrapper token (e.g. WETH), and settle it like a token
+    Wrap(Address),
+}
+
+/// What to do when the commission can not cover the settlement gas
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GasGuard {
+    /// hold the deposit, it can be released when the gas is cheaper
+    Skip,
+    /// raise the commission to the gas cost
+    Raise,
+}
+
 struct Chain {
     chain_type: ChainType,
     chain_name: String,
     _chain_id: u64,
     latency: i64,
-    commission: i32,
+    max_latency: i64,
+    block_time: Duration,
+    commission_bps: i32,
     commission_min: i32,
     commission_max: i32,
     rpc: Url,
+    http: Client,
+    timeout: u64,
+    gas_check: bool,
+    gas: GasStrategy,
+    max_gas_price: Option<u128>,
+    gas_guard: Option<GasGuard>,
+    // the token amount of one native coin, by the token address
+    native_rates: HashMap<Address, f64>,
+    skip_probe: bool,
+    max_tokens: usize,
+    native: Option<NativeSettlement>,
+    native_traces: bool,
     wallet: PrivateKeySigner,
     raw_wallet: String,
+    // the admin wallet transactions are sent one by one, shared with x402 settlements
+    nonce_lock: Arc<Mutex<()>>,
     assets: HashMap<Address, ChainAsset>,
     last_scanned_block: i64,
 }
@@ -107,14 +473,23 @@ pub struct ChainAsset {
 pub struct X402Asset {
     pub ctype: ChainType,
     pub rpc: String,
+    pub timeout: u64,
     pub network: String,
     pub signer: String,
+    pub max_tokens: usize,
+    pub skip_probe: bool,
+    pub gas: GasStrategy,
+    pub max_gas_price: Option<u128>,
+    /// the signer transactions lock, shared with the deposit settlements of the chain
+    pub nonce_lock: Arc<Mutex<()>>,
     pub assets: Vec<ChainAsset>,
 }
 
 pub enum ChainDeposit {
-    // token_address, to_address, amount, tx_hash
-    Evm(Address, Address, U256, B256),
+    // token_address, to_address, amount, tx_hash, log_index
+    Evm(Address, Address, U256, B256, u64),
+    // to_address, native amount, tx_hash, index of the internal call (TRACE_INDEX_BASE + position)
+    EvmNative(Address, U256, B256, Option<u64>),
 }
 
 /// Scanner service message
@@ -123,40 +498,124 @@ pub enum ScannerMessage {
     Deposit(usize, ChainDeposit),
     /// scanned block number
     Scanned(usize, i64),
+    /// re-scan a block range of the chain without moving the scanned block,
+    /// chain_name, from_block, to_block
+    Rescan(String, u64, u64),
+    /// pause scanning the chain, chain_name
+    Pause(String),
+    /// resume scanning the chain, chain_name
+    Resume(String),
+    /// settle the held deposit, deposit_id, asset identity, customer_address
+    Release(i32, String, String),
+}
+
+impl ScannerMessage {
+    // the chain name of the control messages
+    fn chain_name(&self) -> Option<&str> {
+        match self {
+            ScannerMessage::Rescan(name, _, _)
+            | ScannerMessage::Pause(name)
+            | ScannerMessage::Resume(name) => Some(name),
+            _ => None,
+        }
+    }
 }
 
 pub struct ScannerService<S: ScannerStorage> {
     storage: S,
-    mnemonics: String,
+    signer: Arc<dyn CustomerSigner>,
     chains: Vec<Chain>,
 }
 
 impl<S: ScannerStorage> ScannerService<S> {
-    pub async fn new(storage: S, mnemonics: String, config: ScannerConfig) -> Result<Self> {
+    pub async fn new(
+        storage: S,
+        signer: Arc<dyn CustomerSigner>,
+        config: ScannerConfig,
+    ) -> Result<Self> {
         // parse the chain configure
-        let (default_sk, _addr) = generate_eth(0, 0, &mnemonics)?;
-        let default_admin: PrivateKeySigner = default_sk.parse()?;
+        let default_admin = signer.admin();
         let mut chains = vec![];
         for config in config.chains {
             let chain_type = ChainType::from_str(&config.chain_type);
-            let (wallet, raw_wallet): (PrivateKeySigner, String) = if let Some(admin) = config.admin
-            {
-                (admin.parse()?, admin)
-            } else {
-                (default_admin.clone(), default_sk.clone())
-            };
+            let commission_bps = config.commission_in_bps();
+            if !(0..=MAX_COMMISSION_BPS).contains(&commission_bps) {
+                return Err(anyhow::anyhow!(
+                    "{}: commission_bps must be within 0..={MAX_COMMISSION_BPS}",
+                    config.chain_name
+                ));
+            }
+
+            let (wallet, raw_wallet): (PrivateKeySigner, String) =
+                if let Some(admin) = config.admin.clone() {
+                    (admin.parse()?, admin)
+                } else {
+                    default_admin.clone().ok_or(anyhow::anyhow!(
+                        "{}: admin is required without the mnemonics",
+                        config.chain_name
+                    ))?
+                };
             let rpc: Url = config.rpc.parse()?;
-            let provider = ProviderBuilder::new().connect_http(rpc.clone());
+            let timeout = config.timeout.unwrap_or(DEFAULT_RPC_TIMEOUT);
+            let http = Client::builder()
+                .timeout(Duration::from_secs(timeout))
+                .build()?;
+            let provider = ProviderBuilder::new().connect_reqwest(http.clone(), rpc.clone());
             let chain_id = provider.get_chain_id().await?;
 
-            // fetch token decimal and also test the rpc is work
+            let gas = gas_strategy(&config, &provider).await?;
+
+            let block_time_ms = match config.block_time_ms {
+                Some(ms) => ms,
+                None => evm::measure_block_time(&provider, BLOCK_TIME_SPAN)
+                    .await
+                    .unwrap_or_else(|err| {
+                        tracing::warn!(
+                            "{}: failed to measure block time, use {}s: {}",
+                            config.chain_name,
+                            SCAN_INTERVAL_NORMAL,
+                            err
+                        );
+                        SCAN_INTERVAL_NORMAL * 1000
+                    }),
+            };
+
+            let max_tokens = config.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+            if config.tokens.len() > max_tokens {
+                return Err(anyhow::anyhow!(
+                    "{}: {} tokens configured, more than max_tokens {}",
+                    config.chain_name,
+                    config.tokens.len(),
+                    max_tokens
+                ));
+            }
+
+            // fetch all token decimals in parallel, and also test the rpc is work,
+            // the tokens with configured decimals are trusted and not fetched
+            let mut probes = JoinSet::new();
+            let mut decimals = HashMap::new();
+            for t in config.tokens.iter() {
+                let mut values = t.split(":");
+                let token: Address = values.nth(1).unwrap_or_default().parse()?;
+                if let Some(decimal) = values.nth(1) {
+                    decimals.insert(token, decimal.parse::<u8>()?);
+                    continue;
+                }
+                let provider = provider.clone();
+                probes.spawn(async move { (token, evm::get_token_decimal(token, provider).await) });
+            }
+            while let Some(res) = probes.join_next().await {
+                let (token, decimal) = res?;
+                decimals.insert(token, decimal?);
+            }
+
             let mut assets = HashMap::new();
             for t in config.tokens.iter() {
                 let mut values = t.split(":");
                 let name: String = values.next().unwrap_or_default().to_owned();
                 let token: Address = values.next().unwrap_or_default().parse()?;
                 let version = values.next().unwrap_or_default().to_owned(); // EIP-3009 x402
-                let decimal = evm::get_token_decimal(token, provider.clone()).await?;
+                let decimal = decimals[&token];
                 let identity = format!("{}:{}", config.chain_name, name);
 
                 let asset = ChainAsset {
@@ -169,6 +628,46 @@ impl<S: ScannerStorage> ScannerService<S> {
                 assets.insert(token, asset);
             }
 
+            let native = match config.native.as_deref() {
+                None | Some("") => None,
+                Some("sweep") => Some(NativeSettlement::Sweep),
+                Some(wrapper) => Some(NativeSettlement::Wrap(wrapper.parse()?)),
+            };
+
+            let gas_guard = config.gas_guard()?;
+
+            // the rates are by the token names, the deposits of a token without rate are
+            // held when the gas guard is enabled
+            let mut native_rates = HashMap::new();
+            for (name, rate) in config.native_rates.iter().flatten() {
+                let Some((token, _)) = assets.iter().find(|(_, a)| &a.name == name) else {
+                    return Err(anyhow::anyhow!(
+                        "{}: native_rates has unknown token {}",
+                        config.chain_name,
+                        name
+                    ));
+                };
+                if !rate.is_finite() || *rate <= 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "{}: native_rates of {} must be positive",
+                        config.chain_name,
+                        name
+                    ));
+                }
+                native_rates.insert(*token, *rate);
+            }
+            if gas_guard.is_some() {
+                for (token, asset) in assets.iter() {
+                    if !native_rates.contains_key(token) {
+                        tracing::warn!(
+                            "{}: no native_rates of {}, its deposits are held by gas_guard",
+                            config.chain_name,
+                            asset.name
+                        );
+                    }
+                }
+            }
+
             let last_scanned_block = storage.get_scanned_block(&config.chain_name).await?;
 
             chains.push(Chain {
@@ -176,12 +675,29 @@ impl<S: ScannerStorage> ScannerService<S> {
                 chain_name: config.chain_name,
                 _chain_id: chain_id,
                 latency: config.latency as i64,
-                commission: config.commission,
+                max_latency: config
+                    .max_latency
+                    .unwrap_or(config.latency)
+                    .max(config.latency) as i64,
+                block_time: Duration::from_millis(block_time_ms.max(100)),
+                commission_bps,
                 commission_min: config.commission_min,
                 commission_max: config.commission_max,
                 rpc,
+                http,
+                timeout,
+                gas_check: config.gas_check.unwrap_or(true),
+                gas,
+                max_gas_price: config.max_gas_price.map(u128::from),
+                gas_guard,
+                native_rates,
+                skip_probe: config.skip_probe.unwrap_or(false),
+                max_tokens,
+                native,
+                native_traces: config.native_traces.unwrap_or(false),
                 wallet,
                 raw_wallet,
+                nonce_lock: Arc::new(Mutex::new(())),
                 assets,
                 last_scanned_block,
             });
@@ -189,20 +705,57 @@ impl<S: ScannerStorage> ScannerService<S> {
 
         Ok(Self {
             storage,
-            mnemonics,
+            signer,
             chains,
         })
     }
 
+    /// Check the merchant wallet is not a contract on every chain, a contract maybe reject the
+    /// incoming transfers, and every settlement fails. It only warns for the tokens, and fails
+    /// for the swept native currency, unless `allow_contract` is acknowledged
+    pub async fn check_wallet(&self, wallet: &str, allow_contract: bool) -> Result<()> {
+        let merchant: Address = wallet.parse()?;
+        for chain in self.chains.iter() {
+            let provider =
+                ProviderBuilder::new().connect_reqwest(chain.http.clone(), chain.rpc.clone());
+            if !evm::is_contract(merchant, provider).await? {
+                continue;
+            }
+
+            let sweep = matches!(chain.native, Some(NativeSettlement::Sweep));
+            if sweep && !allow_contract {
+                return Err(anyhow::anyhow!(
+                    "{}: wallet {} is a contract, which maybe reject the swept native currency",
+                    chain.chain_name,
+                    wallet
+                ));
+            }
+            if !allow_contract {
+                tracing::warn!(
+                    "{}: wallet {} is a contract, make sure it can receive the settled tokens",
+                    chain.chain_name,
+                    wallet
+                );
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn run(self) -> Result<(UnboundedSender<ScannerMessage>, Vec<X402Asset>)> {
         let (sender, receiver) = unbounded_channel::<ScannerMessage>();
 
         // start chain scanners
         let mut x402_assets = vec![];
+        let mut controls = vec![];
         for (i, chain) in self.chains.iter().enumerate() {
+            let (control, control_receiver) = unbounded_channel::<ScannerMessage>();
             match chain.chain_type {
-                ChainType::Evm => evm::Scanner::new(i, chain, sender.clone()).await?.run(),
+                ChainType::Evm => evm::Scanner::new(i, chain, sender.clone(), control_receiver)
+                    .await?
+                    .run(),
             }
+            controls.push(control);
             tracing::info!(
                 "{} scanning, main account: {}, tokens: {:?}",
                 chain.chain_name,
@@ -219,24 +772,39 @@ impl<S: ScannerStorage> ScannerService<S> {
                 x402_assets.push(X402Asset {
                     ctype: chain.chain_type,
                     rpc: chain.rpc.to_string(),
+                    timeout: chain.timeout,
                     network: chain.chain_name.clone(),
                     signer: chain.raw_wallet.clone(),
+                    max_tokens: chain.max_tokens,
+                    skip_probe: chain.skip_probe,
+                    gas: chain.gas,
+                    max_gas_price: chain.max_gas_price,
+                    nonce_lock: chain.nonce_lock.clone(),
                     assets,
                 })
             }
         }
 
-        tokio::spawn(self.listen(receiver));
+        tokio::spawn(self.listen(receiver, controls));
         Ok((sender, x402_assets))
     }
 
-    async fn listen(self, mut recv: UnboundedReceiver<ScannerMessage>) {
+    async fn listen(
+        self,
+        mut recv: UnboundedReceiver<ScannerMessage>,
+        controls: Vec<UnboundedSender<ScannerMessage>>,
+    ) {
         loop {
             match recv.recv().await {
                 Some(ScannerMessage::Deposit(index, deposit)) => match deposit {
-                    ChainDeposit::Evm(token, customer, value, tx) => {
+                    ChainDeposit::Evm(token, customer, value, tx, log_index) => {
+                        let _ = self
+                            .handle_evm_deposit(index, token, customer, value, tx, log_index)
+                            .await;
+                    }
+                    ChainDeposit::EvmNative(customer, value, tx, call_index) => {
                         let _ = self
-                            .handle_evm_deposit(index, token, customer, value, tx)
+                            .handle_evm_native_deposit(index, customer, value, tx, call_index)
                             .await;
                     }
                 },
@@ -246,6 +814,21 @@ impl<S: ScannerStorage> ScannerService<S> {
                         .set_scanned_block(&self.chains[index].chain_name, block)
                         .await;
                 }
+                Some(ScannerMessage::Release(did, identity, customer)) => {
+                    if let Err(err) = self.handle_release(did, &identity, &customer).await {
+                        tracing::error!("RELEASE: deposit {did} failed: {:?}", err);
+                    }
+                }
+                Some(msg) => {
+                    // forward the control message to the chain scanner
+                    let name = msg.chain_name().unwrap_or_default();
+                    match self.chains.iter().position(|c| c.chain_name == name) {
+                        Some(index) => {
+                            let _ = controls[index].send(msg);
+                        }
+                        None => tracing::error!("Control: no chain {name}"),
+                    }
+                }
                 None => break,
             }
         }
@@ -258,13 +841,15 @@ impl<S: ScannerStorage> ScannerService<S> {
         customer: Address,
         value: U256,
         tx: B256,
+        log_index: u64,
     ) -> Result<()> {
         // 1. check address or transaction is exists
         let cs = customer.to_checksum(None);
         let tx = format!("{:?}", tx);
         let (mid, cid, merchant) = self.storage.contains_address(&cs).await?;
-        self.storage.no_transaction(&tx).await?;
+        self.storage.no_transaction(&tx, Some(log_index)).await?;
         let merchant: Address = merchant.parse()?;
+        tracing::info!("DEPOSIT: {tx}:{log_index} to {cs}");
 
         // 2. save the new deposited
         let chain = &self.chains[index];
@@ -275,30 +860,94 @@ impl<S: ScannerStorage> ScannerService<S> {
         let amount = evm::u256_to_i32(value, &asset.decimal);
         let did = self
             .storage
-            .deposited(asset.identity.clone(), mid, cid, amount, tx.clone())
+            .deposited(
+                asset.identity.clone(),
+                mid,
+                cid,
+                amount,
+                tx.clone(),
+                Some(log_index),
+            )
             .await?;
+        if self.storage.hold(did).await.unwrap_or(false) {
+            tracing::info!("HOLD: deposit {did} {tx}");
+            return Ok(());
+        }
 
-        // 2. generate customer secret key
-        let (sk, _addr) = generate_eth(mid, cid, &self.mnemonics)?;
-        let customer_wallet: PrivateKeySigner = sk.parse()?;
+        self.settle_evm_deposit(index, token, customer, merchant, mid, cid, did)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn settle_evm_deposit(
+        &self,
+        index: usize,
+        token: Address,
+        customer: Address,
+        merchant: Address,
+        mid: i32,
+        cid: i32,
+        did: i32,
+    ) -> Result<()> {
+        let chain = &self.chains[index];
+        let asset = chain
+            .assets
+            .get(&token)
+            .ok_or(anyhow::anyhow!("No token"))?;
+
+        // 2. get the customer wallet
+        let customer_wallet = self.signer.wallet(mid, cid).await?;
+
+        // the gas guard needs the native rate of the token, fail closed without it
+        let gas_guard = match chain.gas_guard {
+            None => None,
+            Some(guard) => match chain.native_rates.get(&token) {
+                Some(rate) => Some((guard, evm::rate_to_u256(*rate, &asset.decimal))),
+                None => {
+                    tracing::warn!("HOLD: deposit {did}: no native rate of {}", asset.name);
+                    let _ = self.storage.set_status(did, SettlementStatus::Held).await;
+                    return Ok(());
+                }
+            },
+        };
 
         // 3. do transfer onchain
-        let (settled_amount, settled_tx) = evm::transfer(
+        let _ = self
+            .storage
+            .set_status(did, SettlementStatus::Settling)
+            .await;
+        let (settled_amount, settled_tx) = match evm::transfer(
             customer,
             merchant,
             token,
             customer_wallet,
             chain.wallet.clone(),
+            chain.http.clone(),
             chain.rpc.clone(),
-            chain.commission,
+            chain.commission_bps,
             evm::i32_to_u256(chain.commission_min, &asset.decimal),
             evm::i32_to_u256(chain.commission_max, &asset.decimal),
+            chain.gas_check,
+            chain.gas,
+            chain.max_gas_price,
+            gas_guard,
+            chain.nonce_lock.clone(),
         )
         .await
-        .map_err(|err| {
-            tracing::error!("TRANSFER: {tx} failed: {:?}", err);
-            err
-        })?;
+        {
+            Ok(res) => res,
+            Err(err) => {
+                // hold it, so it can be released when the gas is cheaper
+                if let Some(TransferError::NetNegative(..)) = err.downcast_ref::<TransferError>() {
+                    tracing::warn!("HOLD: deposit {did}: {err}");
+                    let _ = self.storage.set_status(did, SettlementStatus::Held).await;
+                    return Ok(());
+                }
+                tracing::error!("TRANSFER: deposit {did} failed: {:?}", err);
+                let _ = self.storage.set_status(did, SettlementStatus::Failed).await;
+                return Err(err);
+            }
+        };
 
         // 4. save the settled to deposit
         let settled_amount = evm::u256_to_i32(settled_amount, &asset.decimal);
@@ -310,6 +959,169 @@ impl<S: ScannerStorage> ScannerService<S> {
 
         Ok(())
     }
+
+    async fn handle_evm_native_deposit(
+        &self,
+        index: usize,
+        customer: Address,
+        value: U256,
+        tx: B256,
+        call_index: Option<u64>,
+    ) -> Result<()> {
+        // 1. check address or transaction is exists
+        let cs = customer.to_checksum(None);
+        let tx = format!("{:?}", tx);
+        let (mid, cid, merchant) = self.storage.contains_address(&cs).await?;
+        self.storage.no_transaction(&tx, call_index).await?;
+        let merchant: Address = merchant.parse()?;
+
+        // 2. save the new deposited
+        let chain = &self.chains[index];
+        chain.native.ok_or(anyhow::anyhow!("No native"))?;
+        let identity = format!("{}:{}", chain.chain_name, NATIVE_ASSET);
+        let amount = evm::u256_to_i32(value, &NATIVE_DECIMAL);
+        let did = self
+            .storage
+            .deposited(identity, mid, cid, amount, tx.clone(), call_index)
+            .await?;
+        if self.storage.hold(did).await.unwrap_or(false) {
+            tracing::info!("HOLD: deposit {did} {tx}");
+            return Ok(());
+        }
+
+        self.settle_evm_native(index, customer, merchant, mid, cid, did)
+            .await
+    }
+
+    async fn settle_evm_native(
+        &self,
+        index: usize,
+        customer: Address,
+        merchant: Address,
+        mid: i32,
+        cid: i32,
+        did: i32,
+    ) -> Result<()> {
+        let chain = &self.chains[index];
+        let native = chain.native.ok_or(anyhow::anyhow!("No native"))?;
+        let identity = format!("{}:{}", chain.chain_name, NATIVE_ASSET);
+
+        // 2. get the customer wallet
+        let customer_wallet = self.signer.wallet(mid, cid).await?;
+
+        // 3. do transfer onchain, commission min/max are stablecoin amounts,
+        // so only the commission rate is used for native currency
+        let _ = self
+            .storage
+            .set_status(did, SettlementStatus::Settling)
+            .await;
+        let res = match native {
+            NativeSettlement::Sweep => {
+                evm::sweep(
+                    customer_wallet,
+                    merchant,
+                    chain.wallet.address(),
+                    chain.http.clone(),
+                    chain.rpc.clone(),
+                    chain.commission_bps,
+                    chain.gas,
+                )
+                .await
+            }
+            NativeSettlement::Wrap(wrapper) => {
+                match evm::wrap(
+                    customer_wallet.clone(),
+                    wrapper,
+                    chain.http.clone(),
+                    chain.rpc.clone(),
+                    chain.gas,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        evm::transfer(
+                            customer,
+                            merchant,
+                            wrapper,
+                            customer_wallet,
+                            chain.wallet.clone(),
+                            chain.http.clone(),
+                            chain.rpc.clone(),
+                            chain.commission_bps,
+                            U256::ZERO,
+                            U256::MAX,
+                            chain.gas_check,
+                            chain.gas,
+                            chain.max_gas_price,
+                            None,
+                            chain.nonce_lock.clone(),
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+        };
+        let (settled_amount, settled_tx) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("TRANSFER: deposit {did} failed: {:?}", err);
+                let _ = self.storage.set_status(did, SettlementStatus::Failed).await;
+                return Err(err);
+            }
+        };
+
+        // 4. save the settled to deposit
+        let settled_amount = evm::u256_to_i32(settled_amount, &NATIVE_DECIMAL);
+        let settled_tx = format!("{:?}", settled_tx);
+        let _ = self
+            .storage
+            .settled(identity, did, settled_amount, settled_tx)
+            .await;
+
+        Ok(())
+    }
+
+    // settle the held deposit, the asset identity is `chain_name:token_name`
+    async fn handle_release(&self, did: i32, identity: &str, customer: &str) -> Result<()> {
+        // the release claims the held deposit as settling, only once
+        let status = self.storage.status(did).await?;
+        if status != SettlementStatus::Settling {
+            return Err(anyhow::anyhow!(
+                "Deposit {did} is {}, not claimed for release",
+                status.as_str()
+            ));
+        }
+
+        let (chain_name, name) = identity
+            .split_once(':')
+            .ok_or(anyhow::anyhow!("Invalid asset: {identity}"))?;
+        let index = self
+            .chains
+            .iter()
+            .position(|c| c.chain_name == chain_name)
+            .ok_or(anyhow::anyhow!("No chain: {chain_name}"))?;
+        let customer: Address = customer.parse()?;
+        let (mid, cid, merchant) = self
+            .storage
+            .contains_address(&customer.to_checksum(None))
+            .await?;
+        let merchant: Address = merchant.parse()?;
+
+        if name == NATIVE_ASSET {
+            self.settle_evm_native(index, customer, merchant, mid, cid, did)
+                .await
+        } else {
+            let token = self.chains[index]
+                .assets
+                .iter()
+                .find(|(_, a)| a.identity == identity)
+                .map(|(t, _)| *t)
+                .ok_or(anyhow::anyhow!("No token: {identity}"))?;
+            self.settle_evm_deposit(index, token, customer, merchant, mid, cid, did)
+                .await
+        }
+    }
 }
 
 // pub async fn fetch_gas_token_price() -> Result<i32> {
@@ -317,3 +1129,80 @@ impl<S: ScannerStorage> ScannerService<S> {
 //     let response = reqwest::get(format!("")).await?;
 //     let data = response.json()?.await?;
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settlement_status() {
+        for status in [
+            SettlementStatus::Detected,
+            SettlementStatus::Settling,
+            SettlementStatus::Settled,
+            SettlementStatus::Failed,
+            SettlementStatus::Manual,
+            SettlementStatus::Held,
+        ] {
+            assert_eq!(status.as_str().parse::<SettlementStatus>().unwrap(), status);
+        }
+        assert_eq!(
+            "Settled".parse::<SettlementStatus>().unwrap(),
+            SettlementStatus::Settled
+        );
+        assert!("setled".parse::<SettlementStatus>().is_err());
+        assert!("".parse::<SettlementStatus>().is_err());
+    }
+
+    #[test]
+    fn test_settlement_fee() {
+        let amount = U256::from(10_000);
+        let commission = U256::from(100);
+
+        // no guard or the commission covers the gas
+        assert_eq!(
+            settlement_fee(commission, amount, None).unwrap(),
+            commission
+        );
+        let covered = Some((GasGuard::Skip, U256::from(100)));
+        assert_eq!(
+            settlement_fee(commission, amount, covered).unwrap(),
+            commission
+        );
+
+        // raise to the gas cost, if the amount can pay it
+        let raise = Some((GasGuard::Raise, U256::from(300)));
+        assert_eq!(
+            settlement_fee(commission, amount, raise).unwrap(),
+            U256::from(300)
+        );
+        let raise = Some((GasGuard::Raise, U256::from(10_000)));
+        assert!(matches!(
+            settlement_fee(commission, amount, raise),
+            Err(TransferError::NetNegative(..))
+        ));
+
+        // skip holds the deposit
+        let skip = Some((GasGuard::Skip, U256::from(300)));
+        assert!(matches!(
+            settlement_fee(commission, amount, skip),
+            Err(TransferError::NetNegative(..))
+        ));
+    }
+
+    #[test]
+    fn test_gas_cost() {
+        // 130k gas at 10 gwei, 3000.00 token per native coin: 0.0013 native = 3.90
+        let rate = U256::from(300_000);
+        assert_eq!(
+            gas_cost(130_000, 10_000_000_000, U256::ZERO, rate),
+            U256::from(390)
+        );
+        // with the approve top-up of 0.001 native
+        let extra = U256::from(1_000_000_000_000_000u64);
+        assert_eq!(
+            gas_cost(130_000, 10_000_000_000, extra, rate),
+            U256::from(690)
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::did::generate_eth;
+use alloy::{
+    consensus::SignableTransaction,
+    network::{EthereumWallet, TxSigner},
+    primitives::{Address, B256, Signature},
+    signers::local::PrivateKeySigner,
+    transports::http::reqwest::{Client, Url},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The custody of the customer deposit keys, the deposit address is by the address index
+/// (`mid`) and the customer id (`cid`), the wallet signs its approve, sweep and wrap transactions
+#[async_trait]
+pub trait CustomerSigner: Send + Sync + 'static {
+    /// The checksum deposit address of the customer
+    async fn address(&self, mid: i32, cid: i32) -> Result<String>;
+
+    /// The wallet to sign the transactions of the customer deposit address
+    async fn wallet(&self, mid: i32, cid: i32) -> Result<EthereumWallet>;
+
+    /// The default admin (signer, secret key) of the chains without admin configured
+    fn admin(&self) -> Option<(PrivateKeySigner, String)> {
+        None
+    }
+}
+
+/// The default signer, derives the customer keys from the mnemonics
+pub struct MnemonicSigner {
+    mnemonics: String,
+}
+
+impl MnemonicSigner {
+    pub fn new(mnemonics: String) -> Self {
+        Self { mnemonics }
+    }
+}
+
+#[async_trait]
+impl CustomerSigner for MnemonicSigner {
+    async fn address(&self, mid: i32, cid: i32) -> Result<String> {
+        let (_sk, address) = generate_eth(mid, cid, &self.mnemonics)?;
+        Ok(address)
+    }
+
+    async fn wallet(&self, mid: i32, cid: i32) -> Result<EthereumWallet> {
+        let (sk, _address) = generate_eth(mid, cid, &self.mnemonics)?;
+        let signer: PrivateKeySigner = sk.parse()?;
+        Ok(signer.into())
+    }
+
+    fn admin(&self) -> Option<(PrivateKeySigner, String)> {
+        let (sk, _address) = generate_eth(0, 0, &self.mnemonics).ok()?;
+        Some((sk.parse().ok()?, sk))
+    }
+}
+
+#[derive(Serialize)]
+struct RemoteRequest {
+    /// the address index, increased by every rotation
+    index: i32,
+    customer: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<B256>,
+}
+
+#[derive(Deserialize)]
+struct AddressResponse {
+    address: Address,
+}
+
+#[derive(Deserialize)]
+struct SignatureResponse {
+    signature: Signature,
+}
+
+/// The external signing service, the keys never leave it:
+/// - `POST {url}/address` with `{"index": mid, "customer": cid}` returns `{"address": "0x..."}`
+/// - `POST {url}/sign` with `{"index": mid, "customer": cid, "hash": "0x..."}` returns
+///   `{"signature": "0x..."}`, the 65 bytes signature of the transaction hash
+pub struct RemoteSigner {
+    url: Url,
+    http: Client,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, timeout: u64) -> Result<Self> {
+        let url = url.trim_end_matches('/').parse()?;
+        let http = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+        Ok(Self { url, http })
+    }
+}
+
+async fn remote_call<T: for<'de> Deserialize<'de>>(
+    http: &Client,
+    url: &Url,
+    path: &str,
+    request: &RemoteRequest,
+) -> Result<T> {
+    let res = http
+        .post(format!("{url}/{path}"))
+        .json(request)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(res)
+}
+
+#[async_trait]
+impl CustomerSigner for RemoteSigner {
+    async fn address(&self, mid: i32, cid: i32) -> Result<String> {
+        let request = RemoteRequest {
+            index: mid,
+            customer: cid,
+            hash: None,
+        };
+        let res: AddressResponse = remote_call(&self.http, &self.url, "address", &request).await?;
+        Ok(res.address.to_checksum(None))
+    }
+
+    async fn wallet(&self, mid: i32, cid: i32) -> Result<EthereumWallet> {
+        let address = self.address(mid, cid).await?.parse()?;
+        let signer = RemoteTxSigner {
+            url: self.url.clone(),
+            http: self.http.clone(),
+            mid,
+            cid,
+            address,
+        };
+        Ok(signer.into())
+    }
+}
+
+// The transaction signer of one customer address by the external signing service
+struct RemoteTxSigner {
+    url: Url,
+    http: Client,
+    mid: i32,
+    cid: i32,
+    address: Address,
+}
+
+#[async_trait]
+impl TxSigner<Signature> for RemoteTxSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn sign_transaction(
+        &self,
+        tx: &mut dyn SignableTransaction<Signature>,
+    ) -> alloy::signers::Result<Signature> {
+        let hash = tx.signature_hash();
+        let request = RemoteRequest {
+            index: self.mid,
+            customer: self.cid,
+            hash: Some(hash),
+        };
+        let res: SignatureResponse = remote_call(&self.http, &self.url, "sign", &request)
+            .await
+            .map_err(|err| alloy::signers::Error::other(err.to_string()))?;
+
+        // never broadcast a signature of other key
+        let recovered = res
+            .signature
+            .recover_address_from_prehash(&hash)
+            .map_err(alloy::signers::Error::other)?;
+        if recovered != self.address {
+            return Err(alloy::signers::Error::other(format!(
+                "Remote signature is by {recovered}, not {}",
+                self.address
+            )));
+        }
+
+        Ok(res.signature)
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
-    Authorization, PaymentPayload, PaymentRequirements, SCHEME, SchemePayload, X402_VERSION,
+    Authorization, PaymentPayload, PaymentRequirements, SCHEME, SchemePayload, SettlementReceipt,
+    X402_VERSION,
     scheme::evm::{Eip712Domain, create_eip712_domain, sign_authorization},
+    verify_receipt,
 };
 use alloy::{
     primitives::{Address, keccak256},
@@ -9,7 +11,7 @@ use alloy::{
     transports::http::reqwest::Url,
 };
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Payment method, support evm and sol
 pub enum PaymentMethod {
@@ -32,6 +34,7 @@ enum PaymentInfo {
 /// Main client facilitator used to sign and build payment payload
 pub struct ClientFacilitator {
     infos: HashMap<String, PaymentInfo>,
+    trusted: HashSet<Address>,
 }
 
 impl Default for ClientFacilitator {
@@ -45,9 +48,30 @@ impl ClientFacilitator {
     pub fn new() -> Self {
         Self {
             infos: HashMap::new(),
+            trusted: HashSet::new(),
         }
     }
 
+    /// Trust the facilitator signer address, its settlement receipts are accepted
+    pub fn trust(&mut self, signer: &str) -> Result<()> {
+        self.trusted.insert(signer.parse()?);
+        Ok(())
+    }
+
+    /// Verify the settlement receipt is not modified and signed by a trusted facilitator
+    pub fn verify_settlement(&self, receipt: &SettlementReceipt) -> Result<()> {
+        if !verify_receipt(receipt) {
+            return Err(anyhow::anyhow!("Invalid receipt signature"));
+        }
+
+        let signer: Address = receipt.signer.parse()?;
+        if !self.trusted.contains(&signer) {
+            return Err(anyhow::anyhow!("Untrusted facilitator: {}", signer));
+        }
+
+        Ok(())
+    }
+
     /// Register new payment scheme to it
     ///
     /// # Arguments
@@ -1,13 +1,20 @@
 use crate::{
-    DiscoveryRequest, DiscoveryResponse, Error, Pagination, Payee, PaymentRequirementsResponse,
-    PaymentScheme, SettlementResponse, SupportedResponse, SupportedScheme, VerifyRequest,
+    DiscoveryItem, DiscoveryRequest, DiscoveryResponse, Error, Pagination, Payee,
+    PaymentRequirementsResponse, PaymentScheme, RequirementsFilter, ResourceStorage,
+    ScheduleStorage, SettlementResponse, SupportedResponse, SupportedScheme, VerifyRequest,
     VerifyResponse, X402_VERSION,
 };
-use std::collections::HashMap;
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::RwLock};
+
+/// Seconds to retry a scheduled authorization after a transient failure
+const SCHEDULE_RETRY: u64 = 30;
 
 /// The main facilitator for all payment scheme
 pub struct Facilitator {
     schemes: HashMap<String, Box<dyn PaymentScheme>>,
+    resources: Box<dyn ResourceStorage>,
+    schedules: Box<dyn ScheduleStorage>,
 }
 
 impl Default for Facilitator {
@@ -21,20 +28,47 @@ impl Facilitator {
     pub fn new() -> Self {
         Self {
             schemes: HashMap::new(),
+            resources: Box::new(MemoryStorage::default()),
+            schedules: Box::new(MemoryStorage::default()),
         }
     }
 
+    /// Use the storage for discoverable resources, default is in memory
+    pub fn storage<T: ResourceStorage + 'static>(&mut self, storage: T) {
+        self.resources = Box::new(storage);
+    }
+
+    /// Use the storage for scheduled settlements, default is in memory
+    pub fn schedule_storage<T: ScheduleStorage + 'static>(&mut self, storage: T) {
+        self.schedules = Box::new(storage);
+    }
+
+    /// Add or update a discoverable resource
+    pub async fn add_resource(&self, item: DiscoveryItem) -> anyhow::Result<()> {
+        self.resources.save_resource(item).await
+    }
+
     /// Register new payment scheme to it
     pub fn register<T: PaymentScheme + 'static>(&mut self, scheme: T) {
         let identity = scheme.identity();
         self.schemes.insert(identity, Box::new(scheme));
     }
 
-    /// Create a payment for the client
-    pub fn create(&self, price: &str, payee: Payee) -> PaymentRequirementsResponse {
+    /// Create a payment for the client, only the requirements matching the filter if set
+    pub fn create(
+        &self,
+        price: &str,
+        payee: Payee,
+        filter: Option<&RequirementsFilter>,
+    ) -> PaymentRequirementsResponse {
         let mut payments = Vec::new();
         for (_, scheme) in self.schemes.iter() {
-            payments.extend(scheme.create(price, payee.clone()));
+            payments.extend(
+                scheme
+                    .create(price, payee.clone())
+                    .into_iter()
+                    .filter(|pr| filter.is_none_or(|f| f.matches(pr))),
+            );
         }
 
         PaymentRequirementsResponse {
@@ -57,6 +91,7 @@ impl Facilitator {
                 is_valid: false,
                 invalid_reason: Some(Error::UnsupportedScheme.to_code().0.to_owned()),
                 payer: req.payment_payload.payload.authorization.from.clone(),
+                scheduled_at: None,
             }
         }
     }
@@ -67,16 +102,76 @@ impl Facilitator {
             "{}-{}",
             req.payment_payload.scheme, req.payment_payload.network
         );
-        if let Some(scheme) = self.schemes.get(&identity) {
-            scheme.settle(req).await
-        } else {
-            SettlementResponse {
-                success: false,
-                error_reason: Some(Error::UnsupportedScheme.to_code().0.to_owned()),
-                transaction: "".to_owned(),
-                network: req.payment_payload.network.clone(),
-                payer: req.payment_payload.payload.authorization.from.clone(),
-                feedback_auth: None,
+        let Some(scheme) = self.schemes.get(&identity) else {
+            return Error::UnsupportedScheme.settle(&req.payment_payload);
+        };
+
+        let res = scheme.settle(req).await;
+        if let (true, Some(at)) = (res.pending, res.scheduled_at) {
+            // the same authorization is scheduled once, submit it again is still pending
+            if let Err(err) = self.schedules.save_schedule(req, at).await {
+                tracing::error!("Save x402 schedule: {:?}", err);
+                return Error::UnexpectedSettleError.settle(&req.payment_payload);
+            }
+        }
+        res
+    }
+
+    /// Settle the scheduled authorizations which are due now,
+    /// they are verified again (balance, nonce) before settling
+    pub async fn settle_due(&self) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let due = match self.schedules.due_schedules(now).await {
+            Ok(due) => due,
+            Err(err) => {
+                tracing::error!("List x402 schedules: {:?}", err);
+                return;
+            }
+        };
+
+        for req in due {
+            match self.schedules.claim_schedule(&req).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    tracing::error!("Claim x402 schedule: {:?}", err);
+                    continue;
+                }
+            }
+
+            let id = req.authorization_id();
+            let verified = self.verify(&req).await;
+            let retry = if !verified.is_valid {
+                let reason = verified.invalid_reason.unwrap_or_default();
+                tracing::error!("Scheduled x402 {id} verify failed: {reason}");
+                Error::is_transient(&reason)
+            } else {
+                let settled = self.settle(&req).await;
+                if settled.success {
+                    tracing::info!("Scheduled x402 {id} settled: {}", settled.transaction);
+                    false
+                } else if settled.pending {
+                    true
+                } else {
+                    let reason = settled.error_reason.unwrap_or_default();
+                    tracing::error!("Scheduled x402 {id} settle failed: {reason}");
+                    Error::is_transient(&reason)
+                }
+            };
+
+            // an expired authorization never verifies again, so the retries are bounded
+            let res = if retry {
+                self.schedules
+                    .retry_schedule(&req, now + SCHEDULE_RETRY)
+                    .await
+            } else {
+                self.schedules.finish_schedule(&req).await
+            };
+            if let Err(err) = res {
+                tracing::error!("Update x402 schedule {id}: {:?}", err);
             }
         }
     }
@@ -95,14 +190,26 @@ impl Facilitator {
     }
 
     /// List the discovery response
-    pub fn discovery(&self, req: DiscoveryRequest) -> DiscoveryResponse {
-        let pagination = Pagination {
-            limit: req.limit.unwrap_or(20),
-            offset: req.offset.unwrap_or(0),
-            total: 0,
+    pub async fn discovery(&self, req: DiscoveryRequest) -> DiscoveryResponse {
+        let limit = req.limit.unwrap_or(20).clamp(1, 100);
+        let offset = req.offset.unwrap_or(0).max(0);
+        let (items, total) = match self
+            .resources
+            .list_resources(req.r#type, limit, offset)
+            .await
+        {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("Discovery resources: {:?}", err);
+                (vec![], 0)
+            }
         };
 
-        let items = vec![]; // TODO build the resource
+        let pagination = Pagination {
+            limit,
+            offset,
+            total,
+        };
 
         DiscoveryResponse {
             x402_version: X402_VERSION.to_owned(),
@@ -111,3 +218,108 @@ impl Facilitator {
         }
     }
 }
+
+/// The in-memory resources and schedules storage, they are lost when restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    items: RwLock<Vec<DiscoveryItem>>,
+    schedules: RwLock<HashMap<String, (u64, VerifyRequest)>>,
+}
+
+#[async_trait]
+impl ResourceStorage for MemoryStorage {
+    async fn save_resource(&self, item: DiscoveryItem) -> anyhow::Result<()> {
+        let mut items = self
+            .items
+            .write()
+            .map_err(|_| anyhow::anyhow!("Resources lock poisoned"))?;
+        items.retain(|i| i.resource != item.resource);
+        items.push(item);
+        Ok(())
+    }
+
+    async fn list_resources(
+        &self,
+        r#type: Option<String>,
+        limit: i32,
+        offset: i32,
+    ) -> anyhow::Result<(Vec<DiscoveryItem>, i32)> {
+        let items = self
+            .items
+            .read()
+            .map_err(|_| anyhow::anyhow!("Resources lock poisoned"))?;
+        let filtered: Vec<&DiscoveryItem> = items
+            .iter()
+            .filter(|i| r#type.as_ref().is_none_or(|t| &i.r#type == t))
+            .collect();
+        let total = filtered.len() as i32;
+        let page = filtered
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        Ok((page, total))
+    }
+}
+
+#[async_trait]
+impl ScheduleStorage for MemoryStorage {
+    async fn save_schedule(&self, req: &VerifyRequest, at: u64) -> anyhow::Result<bool> {
+        let mut schedules = self
+            .schedules
+            .write()
+            .map_err(|_| anyhow::anyhow!("Schedules lock poisoned"))?;
+        let id = req.authorization_id();
+        if schedules.contains_key(&id) {
+            return Ok(false);
+        }
+        schedules.insert(id, (at, req.clone()));
+        Ok(true)
+    }
+
+    async fn due_schedules(&self, now: u64) -> anyhow::Result<Vec<VerifyRequest>> {
+        let schedules = self
+            .schedules
+            .read()
+            .map_err(|_| anyhow::anyhow!("Schedules lock poisoned"))?;
+        Ok(schedules
+            .values()
+            .filter(|(at, _)| *at <= now)
+            .map(|(_, req)| req.clone())
+            .collect())
+    }
+
+    async fn claim_schedule(&self, req: &VerifyRequest) -> anyhow::Result<bool> {
+        let mut schedules = self
+            .schedules
+            .write()
+            .map_err(|_| anyhow::anyhow!("Schedules lock poisoned"))?;
+        // the claimed one is never due until it is retried
+        match schedules.get_mut(&req.authorization_id()) {
+            Some((at, _)) if *at != u64::MAX => {
+                *at = u64::MAX;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn retry_schedule(&self, req: &VerifyRequest, at: u64) -> anyhow::Result<()> {
+        let mut schedules = self
+            .schedules
+            .write()
+            .map_err(|_| anyhow::anyhow!("Schedules lock poisoned"))?;
+        schedules.insert(req.authorization_id(), (at, req.clone()));
+        Ok(())
+    }
+
+    async fn finish_schedule(&self, req: &VerifyRequest) -> anyhow::Result<()> {
+        let mut schedules = self
+            .schedules
+            .write()
+            .map_err(|_| anyhow::anyhow!("Schedules lock poisoned"))?;
+        schedules.remove(&req.authorization_id());
+        Ok(())
+    }
+}
@@ -1,18 +1,34 @@
 mod scheme;
-pub use scheme::evm::{Evm8004Registry, EvmAsset, EvmScheme};
+pub use scheme::evm::{
+    DEFAULT_MAX_REQUIRED_TOKENS, DEFAULT_RPC_TIMEOUT, Evm8004Registry, EvmAsset, EvmAssetSpec,
+    EvmScheme, timed,
+};
+pub use scheme::nft::{
+    NftScheme, NftStandard, ownership_domain, sign_ownership_challenge, verify_ownership_challenge,
+};
 pub use scheme::sol::SolScheme;
+pub use scheme::submitter::{
+    DirectSubmitter, Fees, GasStrategy, RECEIPT_TIMEOUT, RelayerSubmitter, Submitter,
+};
 
 pub mod client;
 pub mod facilitator;
-pub use facilitator::Facilitator;
+pub mod paywall;
+pub use facilitator::{Facilitator, MemoryStorage};
+pub use paywall::{RequirementsResolver, X402Layer};
 
+use alloy::{
+    primitives::{Address, U256},
+    signers::{Signature, SignerSync, local::PrivateKeySigner},
+};
 use async_trait::async_trait;
 use eip8004::FeedbackAuth;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Value, value::RawValue};
 
 pub const X402_VERSION: i32 = 1;
 pub const SCHEME: &str = "exact";
+pub const NFT_SCHEME: &str = "nft";
 
 /// When a resource server requires payment, it responds with a payment required signal and a JSON payload containing payment requirements
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,30 +51,59 @@ pub struct PaymentRequirements {
     /// Blockchain network identifier (e.g., "base-sepolia", "ethereum-mainnet")
     pub network: String,
     /// Required payment amount in atomic token units
+    #[serde(alias = "max_amount_required", deserialize_with = "string_or_number")]
     pub max_amount_required: String,
     /// Token contract address
     pub asset: String,
     /// Recipient wallet address for the payment
+    #[serde(alias = "pay_to")]
     pub pay_to: String,
     /// URL of the protected resource
     pub resource: String,
     /// Human-readable description of the resource
     pub description: String,
     /// MIME type of the expected response
+    #[serde(alias = "mime_type")]
     pub mime_type: Option<String>,
     /// JSON schema describing the response format
+    #[serde(alias = "output_schema")]
     pub output_schema: Option<Value>,
     /// Maximum time allowed for payment completion
+    #[serde(alias = "max_timeout_seconds")]
     pub max_timeout_seconds: i32,
     /// Scheme-specific additional information
     pub extra: Option<Value>,
 }
 
+impl PaymentRequirements {
+    /// Set the JSON Schema of the resource response, the schema is checked against its meta-schema
+    pub fn with_output_schema(mut self, schema: Value) -> anyhow::Result<Self> {
+        jsonschema::meta::validate(&schema)
+            .map_err(|e| anyhow::anyhow!("Invalid output schema: {e}"))?;
+        self.output_schema = Some(schema);
+        Ok(self)
+    }
+
+    /// Validate the paid resource response against the advertised output schema,
+    /// any response is valid when no output schema
+    pub fn validate_response(&self, response: &Value) -> anyhow::Result<()> {
+        let Some(schema) = &self.output_schema else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| anyhow::anyhow!("Invalid output schema: {e}"))?;
+        validator
+            .validate(response)
+            .map_err(|e| anyhow::anyhow!("Invalid response: {e}"))
+    }
+}
+
 /// The client includes payment authorization as JSON in the payment payload field
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PaymentPayload {
     /// Protocol version identifier (must be 1)
+    #[serde(alias = "x402_version")]
     pub x402_version: i32,
     /// Payment scheme identifier (e.g., "exact")
     pub scheme: String,
@@ -79,6 +124,7 @@ pub struct SchemePayload {
     /// The index in client feedback for 8004 Reputation,
     /// If has this field, and service has register agent info,
     /// The `SettlementResponse` will has `feedback_auth` field.
+    #[serde(alias = "feedback_index")]
     pub feedback_index: Option<u64>,
 }
 
@@ -91,25 +137,74 @@ pub struct Authorization {
     /// Recipient's wallet address
     pub to: String,
     /// Payment amount in atomic units
+    #[serde(deserialize_with = "string_or_number")]
     pub value: String,
     /// Unix timestamp when authorization becomes valid
+    #[serde(alias = "valid_after", deserialize_with = "string_or_number")]
     pub valid_after: String,
     /// Unix timestamp when authorization expires
+    #[serde(alias = "valid_before", deserialize_with = "string_or_number")]
     pub valid_before: String,
-    /// 32-byte random nonce to prevent replay attacks
+    /// 32-byte random nonce to prevent replay attacks,
+    /// the hex with or without `0x` is accepted, and normalized to the lowercase `0x` hex
+    #[serde(deserialize_with = "nonce_hex")]
     pub nonce: String,
 }
 
+/// The nonce must be exactly 32 bytes of hex, normalized so the same nonce is always the same
+/// string, e.g. in the verification cache key
+fn nonce_hex<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let hex = s.strip_prefix("0x").unwrap_or(&s);
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(serde::de::Error::custom(format!(
+            "nonce must be 32 bytes of hex, got {s:?}"
+        )));
+    }
+    Ok(format!("0x{}", hex.to_lowercase()))
+}
+
+/// Some clients send the amounts and timestamps as JSON numbers, accept both and keep the string.
+/// The number is read from the raw JSON, so an amount above u64 (e.g. 18 decimals) is exact
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = Box::<RawValue>::deserialize(deserializer)?;
+    let text = raw.get();
+    if text.starts_with('"') {
+        return serde_json::from_str(text).map_err(serde::de::Error::custom);
+    }
+    U256::from_str_radix(text, 10)
+        .map(|n| n.to_string())
+        .map_err(|_| {
+            serde::de::Error::custom(format!("expected a string or unsigned integer, got {text}"))
+        })
+}
+
 /// The request of verify and settle payment by scheme
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VerifyRequest {
     /// The payload information
+    #[serde(alias = "payment_payload")]
     pub payment_payload: PaymentPayload,
     /// The payment requirement
+    #[serde(alias = "payment_requirements")]
     pub payment_requirements: PaymentRequirements,
 }
 
+impl VerifyRequest {
+    /// The authorization is identified by the payer and the nonce
+    pub fn authorization_id(&self) -> String {
+        let auth = &self.payment_payload.payload.authorization;
+        format!("{}:{}", auth.from.to_lowercase(), auth.nonce.to_lowercase())
+    }
+}
+
 /// The response of verify payment
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -120,18 +215,24 @@ pub struct VerifyResponse {
     pub payer: String,
     /// Error reason if verify failed (omitted if successful)
     pub invalid_reason: Option<String>,
+    /// Unix timestamp when the future-dated authorization will be settled (omitted if not scheduled)
+    pub scheduled_at: Option<u64>,
 }
 
 impl VerifyResponse {
     /// convert verify response to settle
     pub fn to_settle(self, network: &str, tx: &str) -> SettlementResponse {
+        let pending = self.is_valid && self.scheduled_at.is_some();
         SettlementResponse {
-            success: self.is_valid,
+            success: self.is_valid && !pending,
+            pending,
             error_reason: self.invalid_reason,
             transaction: tx.to_owned(),
             network: network.to_owned(),
             payer: self.payer,
             feedback_auth: None,
+            scheduled_at: self.scheduled_at,
+            receipt: None,
         }
     }
 }
@@ -142,6 +243,10 @@ impl VerifyResponse {
 pub struct SettlementResponse {
     /// Indicates whether the payment settlement was successful
     pub success: bool,
+    /// The future-dated authorization is scheduled and not settled yet,
+    /// `success` is false until it is settled
+    #[serde(default)]
+    pub pending: bool,
     /// Error reason if settlement failed (omitted if successful)
     pub error_reason: Option<String>,
     /// Blockchain transaction hash (empty string if settlement failed)
@@ -152,6 +257,95 @@ pub struct SettlementResponse {
     pub payer: String,
     /// The feedback authorized signature for 8004 Reputation
     pub feedback_auth: Option<FeedbackAuth>,
+    /// Unix timestamp when the future-dated authorization will be settled,
+    /// the transaction is empty until then (omitted if not scheduled)
+    pub scheduled_at: Option<u64>,
+    /// The facilitator signed receipt (omitted if not settled)
+    pub receipt: Option<SettlementReceipt>,
+}
+
+/// Proof of a settled payment, signed by the facilitator with EIP-191
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SettlementReceipt {
+    /// Address of the payer's wallet
+    pub payer: String,
+    /// Recipient wallet address of the payment
+    pub pay_to: String,
+    /// Token contract address
+    pub asset: String,
+    /// Paid amount in atomic token units
+    pub amount: String,
+    /// Blockchain network identifier
+    pub network: String,
+    /// Settlement transaction hash
+    pub transaction: String,
+    /// Unix timestamp of the settlement
+    pub timestamp: u64,
+    /// Address of the facilitator signer
+    pub signer: String,
+    /// EIP-191 signature of the receipt message
+    pub signature: String,
+}
+
+impl SettlementReceipt {
+    /// Build the receipt and sign it by the facilitator signer
+    #[allow(clippy::too_many_arguments)]
+    pub fn sign(
+        payer: &str,
+        pay_to: &str,
+        asset: &str,
+        amount: &str,
+        network: &str,
+        transaction: &str,
+        timestamp: u64,
+        signer: &PrivateKeySigner,
+    ) -> anyhow::Result<Self> {
+        let mut receipt = SettlementReceipt {
+            payer: payer.to_owned(),
+            pay_to: pay_to.to_owned(),
+            asset: asset.to_owned(),
+            amount: amount.to_owned(),
+            network: network.to_owned(),
+            transaction: transaction.to_owned(),
+            timestamp,
+            signer: signer.address().to_checksum(None),
+            signature: String::new(),
+        };
+        let signature = signer.sign_message_sync(receipt.message().as_bytes())?;
+        receipt.signature = signature.to_string();
+        Ok(receipt)
+    }
+
+    /// The signed message, all fields except the signature in fixed order
+    pub fn message(&self) -> String {
+        format!(
+            "x402 settlement receipt\nnetwork: {}\ntransaction: {}\npayer: {}\npayTo: {}\nasset: {}\namount: {}\ntimestamp: {}\nsigner: {}",
+            self.network,
+            self.transaction,
+            self.payer,
+            self.pay_to,
+            self.asset,
+            self.amount,
+            self.timestamp,
+            self.signer
+        )
+    }
+}
+
+/// Verify the receipt is signed by its signer and not modified,
+/// the caller should also check the signer is a trusted facilitator
+pub fn verify_receipt(receipt: &SettlementReceipt) -> bool {
+    let (Ok(signature), Ok(signer)) = (
+        receipt.signature.parse::<Signature>(),
+        receipt.signer.parse::<Address>(),
+    ) else {
+        return false;
+    };
+
+    signature
+        .recover_address_from_msg(receipt.message().as_bytes())
+        .is_ok_and(|addr| addr == signer)
 }
 
 /// List supported payment schemes.
@@ -329,12 +523,26 @@ impl Error {
         }
     }
 
+    /// Whether the error reason may pass later (e.g. the payer funds the account, the RPC recovers),
+    /// the others (e.g. expired, nonce used) never pass
+    pub fn is_transient(reason: &str) -> bool {
+        [
+            Error::InsufficientFunds,
+            Error::InvalidTransactionState,
+            Error::UnexpectedVerifyError,
+            Error::UnexpectedSettleError,
+        ]
+        .iter()
+        .any(|e| e.to_code().0 == reason)
+    }
+
     /// Helper build a verify error
     pub fn verify(&self, req: &PaymentPayload) -> VerifyResponse {
         VerifyResponse {
             is_valid: false,
             payer: req.payload.authorization.from.clone(),
             invalid_reason: Some(self.to_code().0.to_owned()),
+            scheduled_at: None,
         }
     }
 
@@ -342,11 +550,14 @@ impl Error {
     pub fn settle(&self, req: &PaymentPayload) -> SettlementResponse {
         SettlementResponse {
             success: false,
+            pending: false,
             error_reason: Some(self.to_code().0.to_owned()),
             transaction: "".to_owned(),
             network: req.network.clone(),
             payer: req.payload.authorization.from.clone(),
             feedback_auth: None,
+            scheduled_at: None,
+            receipt: None,
         }
     }
 }
@@ -361,6 +572,83 @@ pub struct Payee {
     pub sol: Option<String>,
 }
 
+/// Filter the payment requirements by the tokens and networks the client can pay
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequirementsFilter {
+    /// token symbols (e.g. "USDC"), names or addresses, any token if empty
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// networks (e.g. "base-sepolia"), any network if empty
+    #[serde(default)]
+    pub networks: Vec<String>,
+}
+
+impl RequirementsFilter {
+    /// Check the requirements match the filter, the tokens and networks are case-insensitive
+    pub fn matches(&self, requirements: &PaymentRequirements) -> bool {
+        let network = self.networks.is_empty()
+            || self
+                .networks
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(&requirements.network));
+
+        let extra = |key: &str| {
+            requirements
+                .extra
+                .as_ref()
+                .and_then(|e| e[key].as_str())
+                .unwrap_or_default()
+                .to_owned()
+        };
+        let (symbol, name) = (extra("symbol"), extra("name"));
+        let token = self.tokens.is_empty()
+            || self.tokens.iter().any(|t| {
+                t.eq_ignore_ascii_case(&requirements.asset)
+                    || (!symbol.is_empty() && t.eq_ignore_ascii_case(&symbol))
+                    || (!name.is_empty() && t.eq_ignore_ascii_case(&name))
+            });
+
+        network && token
+    }
+}
+
+/// The discoverable resources storage interface, used by facilitator
+#[async_trait]
+pub trait ResourceStorage: Send + Sync {
+    /// Save the resource, replace it if the resource is exists
+    async fn save_resource(&self, item: DiscoveryItem) -> anyhow::Result<()>;
+
+    /// List the resources filter by type, return the items and total number
+    async fn list_resources(
+        &self,
+        r#type: Option<String>,
+        limit: i32,
+        offset: i32,
+    ) -> anyhow::Result<(Vec<DiscoveryItem>, i32)>;
+}
+
+/// The scheduled settlements storage interface, used by facilitator,
+/// an authorization is scheduled once by its payer and nonce
+#[async_trait]
+pub trait ScheduleStorage: Send + Sync {
+    /// Save the authorization to settle at the time, return false if it is scheduled already
+    async fn save_schedule(&self, req: &VerifyRequest, at: u64) -> anyhow::Result<bool>;
+
+    /// List the scheduled authorizations due at the time
+    async fn due_schedules(&self, now: u64) -> anyhow::Result<Vec<VerifyRequest>>;
+
+    /// Take the scheduled authorization out of the due list before settling it,
+    /// return false if it is taken already (e.g. by another facilitator)
+    async fn claim_schedule(&self, req: &VerifyRequest) -> anyhow::Result<bool>;
+
+    /// Put the claimed authorization back to the due list at the time, after a transient failure
+    async fn retry_schedule(&self, req: &VerifyRequest, at: u64) -> anyhow::Result<()>;
+
+    /// Remove the claimed authorization, it is settled or can never be settled
+    async fn finish_schedule(&self, req: &VerifyRequest) -> anyhow::Result<()>;
+}
+
 /// The payment scheme interface
 #[async_trait]
 pub trait PaymentScheme: Send + Sync {
@@ -392,3 +680,87 @@ pub trait PaymentScheme: Send + Sync {
     /// parameters provided in the payment payload.
     async fn settle(&self, req: &VerifyRequest) -> SettlementResponse;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn authorization(
+        value: &str,
+        valid_after: &str,
+        nonce: &str,
+    ) -> serde_json::Result<Authorization> {
+        serde_json::from_str(&format!(
+            r#"{{"from":"0x01","to":"0x02","value":{value},"validAfter":{valid_after},"valid_before":"9999999999","nonce":"{nonce}"}}"#
+        ))
+    }
+
+    #[test]
+    fn test_string_amounts() {
+        let auth = authorization(r#""10000""#, r#""0""#, NONCE).unwrap();
+        assert_eq!(auth.value, "10000");
+        assert_eq!(auth.valid_after, "0");
+        assert_eq!(auth.valid_before, "9999999999");
+    }
+
+    #[test]
+    fn test_number_amounts() {
+        let auth = authorization("10000", "0", NONCE).unwrap();
+        assert_eq!(auth.value, "10000");
+        assert_eq!(auth.valid_after, "0");
+
+        // 18 decimals amount above u64 is kept exactly
+        let auth = authorization("1000000000000000000000001", "0", NONCE).unwrap();
+        assert_eq!(auth.value, "1000000000000000000000001");
+
+        assert!(authorization("-1", "0", NONCE).is_err());
+        assert!(authorization("1.5", "0", NONCE).is_err());
+        assert!(authorization("1e18", "0", NONCE).is_err());
+    }
+
+    #[test]
+    fn test_nonce_hex() {
+        let hex = "ab".repeat(32);
+        assert_eq!(
+            authorization("1", "0", &hex).unwrap().nonce,
+            format!("0x{hex}")
+        );
+
+        // mixed case is normalized to lowercase 0x hex
+        let mixed = format!("0x{}", "aB".repeat(32));
+        assert_eq!(
+            authorization("1", "0", &mixed).unwrap().nonce,
+            format!("0x{hex}")
+        );
+
+        // short or not hex
+        assert!(authorization("1", "0", "0x1234").is_err());
+        assert!(authorization("1", "0", &"ab".repeat(33)).is_err());
+        assert!(authorization("1", "0", &format!("0x{}", "zz".repeat(32))).is_err());
+        assert!(authorization("1", "0", &format!("0X{hex}")).is_err());
+    }
+
+    #[test]
+    fn test_number_max_amount_required() {
+        let requirements: PaymentRequirements = serde_json::from_str(
+            r#"{"scheme":"exact","network":"base","max_amount_required":5000000000000000000000,
+            "asset":"0x03","payTo":"0x02","resource":"","description":"","maxTimeoutSeconds":300}"#,
+        )
+        .unwrap();
+        assert_eq!(requirements.max_amount_required, "5000000000000000000000");
+    }
+
+    #[test]
+    fn test_error_is_transient() {
+        assert!(Error::is_transient("insufficient_funds"));
+        assert!(Error::is_transient("unexpected_settle_error"));
+        // expired or the nonce used, never settled
+        assert!(!Error::is_transient(
+            "invalid_exact_evm_payload_authorization_valid_before"
+        ));
+        assert!(!Error::is_transient("invalid_exact_evm_payload_signature"));
+        assert!(!Error::is_transient(""));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{
+    Error, Facilitator, PaymentPayload, PaymentRequirements, PaymentRequirementsResponse,
+    VerifyRequest, X402_VERSION,
+};
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// The request header of the base64 encoded payment payload
+pub const PAYMENT_HEADER: &str = "X-PAYMENT";
+
+/// The response header of the base64 encoded settlement response
+pub const PAYMENT_RESPONSE_HEADER: &str = "X-PAYMENT-RESPONSE";
+
+/// Resolve the payment requirements of the incoming request, so every route can have its own
+/// price, description, mime_type and output_schema. None means the route is free.
+/// An empty `resource` is filled by the request URL
+pub trait RequirementsResolver: Send + Sync + 'static {
+    fn requirements(&self, req: &Request<Body>) -> Option<Vec<PaymentRequirements>>;
+}
+
+impl<F> RequirementsResolver for F
+where
+    F: Fn(&Request<Body>) -> Option<Vec<PaymentRequirements>> + Send + Sync + 'static,
+{
+    fn requirements(&self, req: &Request<Body>) -> Option<Vec<PaymentRequirements>> {
+        self(req)
+    }
+}
+
+/// The 402 paywall middleware, it verifies and settles the `X-PAYMENT` header before the route,
+/// so the route never serves an unpaid request. The settled payment is not refunded if the
+/// route fails, and the same authorization can not be settled twice (the nonce is used onchain)
+#[derive(Clone)]
+pub struct X402Layer {
+    facilitator: Arc<Facilitator>,
+    resolver: Arc<dyn RequirementsResolver>,
+}
+
+impl X402Layer {
+    /// Build the paywall by the facilitator and the per-route requirements resolver
+    pub fn new<R: RequirementsResolver>(facilitator: Arc<Facilitator>, resolver: R) -> Self {
+        Self {
+            facilitator,
+            resolver: Arc::new(resolver),
+        }
+    }
+}
+
+impl<S> Layer<S> for X402Layer {
+    type Service = X402Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        X402Service {
+            inner,
+            facilitator: self.facilitator.clone(),
+            resolver: self.resolver.clone(),
+        }
+    }
+}
+
+/// The paywall service built by `X402Layer`
+#[derive(Clone)]
+pub struct X402Service<S> {
+    inner: S,
+    facilitator: Arc<Facilitator>,
+    resolver: Arc<dyn RequirementsResolver>,
+}
+
+impl<S> Service<Request<Body>> for X402Service<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // use the ready service, and keep a fresh clone for the next call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let facilitator = self.facilitator.clone();
+        let resolver = self.resolver.clone();
+
+        Box::pin(async move {
+            let Some(mut accepts) = resolver.requirements(&req) else {
+                return inner.call(req).await;
+            };
+            let url = request_url(&req);
+            for pr in accepts.iter_mut() {
+                if pr.resource.is_empty() {
+                    pr.resource = url.clone();
+                }
+            }
+
+            // 1. decode the payment payload
+            let Some(header) = req.headers().get(PAYMENT_HEADER) else {
+                return Ok(payment_required(accepts, "X-PAYMENT header is required"));
+            };
+            let Some(payload) = STANDARD
+                .decode(header.as_bytes())
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<PaymentPayload>(&bytes).ok())
+            else {
+                return Ok(payment_required(accepts, "Invalid X-PAYMENT header"));
+            };
+
+            // 2. verify by the matched requirements
+            let Some(requirements) = accepts
+                .iter()
+                .find(|pr| pr.scheme == payload.scheme && pr.network == payload.network)
+                .cloned()
+            else {
+                return Ok(payment_required(accepts, "Unsupported scheme or network"));
+            };
+            let verify = VerifyRequest {
+                payment_payload: payload,
+                payment_requirements: requirements,
+            };
+            let verified = facilitator.verify(&verify).await;
+            if !verified.is_valid {
+                let reason = verified.invalid_reason.unwrap_or_default();
+                return Ok(payment_required(accepts, &reason));
+            }
+            // the future-dated payment is not settled now, so it can not pay the route
+            if verified.scheduled_at.is_some() {
+                let reason = Error::InvalidExactEvmPayloadAuthorizationValidAfter
+                    .to_code()
+                    .0;
+                return Ok(payment_required(accepts, reason));
+            }
+
+            // 3. settle before the route, a retried request with the same payment fails here
+            let settled = facilitator.settle(&verify).await;
+            if !settled.success {
+                let reason = settled.error_reason.unwrap_or_default();
+                return Ok(payment_required(accepts, &reason));
+            }
+
+            let mut response = inner.call(req).await?;
+            let encoded = STANDARD.encode(serde_json::to_vec(&settled).unwrap_or_default());
+            if let Ok(value) = HeaderValue::from_str(&encoded) {
+                response
+                    .headers_mut()
+                    .insert(PAYMENT_RESPONSE_HEADER, value);
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+// The full request url, the scheme is from X-Forwarded-Proto behind a proxy
+fn request_url(req: &Request<Body>) -> String {
+    let uri = req.uri();
+    if uri.authority().is_some() {
+        return uri.to_string();
+    }
+
+    let headers = req.headers();
+    match headers.get(header::HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => {
+            let scheme = headers
+                .get("X-Forwarded-Proto")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("http");
+            format!("{scheme}://{host}{uri}")
+        }
+        None => uri.to_string(),
+    }
+}
+
+// The 402 response with the accepted payment requirements
+fn payment_required(accepts: Vec<PaymentRequirements>, error: &str) -> Response {
+    let body = PaymentRequirementsResponse {
+        x402_version: X402_VERSION,
+        error: error.to_owned(),
+        accepts,
+    };
+    (StatusCode::PAYMENT_REQUIRED, axum::Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Authorization, Payee, PaymentScheme, SchemePayload, SettlementResponse, VerifyResponse,
+    };
+    use async_trait::async_trait;
+    use axum::{Router, routing::get};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    // The scheme accepts the "paid" signature, and counts the settlements
+    struct MockScheme {
+        settled: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl PaymentScheme for MockScheme {
+        fn scheme(&self) -> &str {
+            "exact"
+        }
+
+        fn network(&self) -> &str {
+            "base"
+        }
+
+        fn create(&self, price: &str, payee: Payee) -> Vec<PaymentRequirements> {
+            vec![PaymentRequirements {
+                scheme: "exact".to_owned(),
+                network: "base".to_owned(),
+                max_amount_required: price.to_owned(),
+                asset: "0x0000000000000000000000000000000000000001".to_owned(),
+                pay_to: payee.evm.unwrap_or_default(),
+                resource: String::new(),
+                description: String::new(),
+                mime_type: None,
+                output_schema: None,
+                max_timeout_seconds: 300,
+                extra: None,
+            }]
+        }
+
+        async fn verify(&self, req: &VerifyRequest) -> VerifyResponse {
+            let payload = &req.payment_payload;
+            if payload.payload.signature != "paid" {
+                return Error::InvalidExactEvmPayloadSignature.verify(payload);
+            }
+            VerifyResponse {
+                is_valid: true,
+                payer: payload.payload.authorization.from.clone(),
+                invalid_reason: None,
+                scheduled_at: None,
+            }
+        }
+
+        async fn settle(&self, req: &VerifyRequest) -> SettlementResponse {
+            self.settled.fetch_add(1, Ordering::SeqCst);
+            self.verify(req)
+                .await
+                .to_settle(&req.payment_payload.network, "0xtx")
+        }
+    }
+
+    fn router(settled: Arc<AtomicUsize>, status: StatusCode) -> Router {
+        let mut facilitator = Facilitator::new();
+        facilitator.register(MockScheme { settled });
+        let facilitator = Arc::new(facilitator);
+        let f = facilitator.clone();
+        let paywall = X402Layer::new(facilitator, move |_req: &Request<Body>| {
+            let payee = Payee {
+                evm: Some("0x0000000000000000000000000000000000000002".to_owned()),
+                sol: None,
+            };
+            Some(f.create("10000", payee, None).accepts)
+        });
+        Router::new()
+            .route("/premium", get(move || async move { status }))
+            .layer(paywall)
+    }
+
+    fn payment(signature: &str) -> String {
+        let payload = PaymentPayload {
+            x402_version: 1,
+            scheme: "exact".to_owned(),
+            network: "base".to_owned(),
+            payload: SchemePayload {
+                signature: signature.to_owned(),
+                authorization: Authorization {
+                    from: "0x0000000000000000000000000000000000000003".to_owned(),
+                    to: "0x0000000000000000000000000000000000000002".to_owned(),
+                    value: "10000".to_owned(),
+                    valid_after: "0".to_owned(),
+                    valid_before: "9999999999".to_owned(),
+                    nonce: format!("0x{}", "11".repeat(32)),
+                },
+                feedback_index: None,
+            },
+        };
+        STANDARD.encode(serde_json::to_vec(&payload).unwrap())
+    }
+
+    fn request(payment: Option<String>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/premium");
+        if let Some(payment) = payment {
+            builder = builder.header(PAYMENT_HEADER, payment);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_paid_request_is_settled_before_route() {
+        let settled = Arc::new(AtomicUsize::new(0));
+        let res = router(settled.clone(), StatusCode::OK)
+            .oneshot(request(Some(payment("paid"))))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key(PAYMENT_RESPONSE_HEADER));
+        assert_eq!(settled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_route_keeps_settlement() {
+        let settled = Arc::new(AtomicUsize::new(0));
+        let res = router(settled.clone(), StatusCode::INTERNAL_SERVER_ERROR)
+            .oneshot(request(Some(payment("paid"))))
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(settled.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unpaid_request_is_rejected() {
+        let settled = Arc::new(AtomicUsize::new(0));
+        let res = router(settled.clone(), StatusCode::OK)
+            .oneshot(request(None))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let res = router(settled.clone(), StatusCode::OK)
+            .oneshot(request(Some(payment("forged"))))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(settled.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
-    Authorization, Error, Payee, PaymentRequirements, PaymentScheme, SCHEME, SettlementResponse,
-    VerifyRequest, VerifyResponse,
+    Authorization, DirectSubmitter, Error, GasStrategy, Payee, PaymentRequirements, PaymentScheme,
+    RelayerSubmitter, SCHEME, SettlementReceipt, SettlementResponse, Submitter, VerifyRequest,
+    VerifyResponse,
 };
 use alloy::{
     primitives::{Address, B256, Bytes, U256},
@@ -8,14 +9,30 @@ use alloy::{
     signers::{Signature, SignerSync, local::PrivateKeySigner},
     sol,
     sol_types::{SolStruct, eip712_domain},
-    transports::http::reqwest::Url,
+    transports::http::reqwest::{Client, Url},
 };
 use anyhow::Result;
 use async_trait::async_trait;
 use eip8004::{FeedbackAuth, FeedbackOnchainAuth};
 use serde_json::{Value, json};
 use std::collections::HashMap;
+use std::future::IntoFuture;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Default timeout of every rpc request (seconds)
+pub const DEFAULT_RPC_TIMEOUT: u64 = 30;
+
+/// Default max assets of a scheme, bound the startup probing
+pub const DEFAULT_MAX_ASSETS: usize = 50;
+
+/// Default max required amount in whole tokens, a larger one is a wrong decimals scale
+pub const DEFAULT_MAX_REQUIRED_TOKENS: u64 = 1_000_000;
+
+/// Min required amount is 10^-6 of a token, a smaller one is a wrong decimals scale
+const MIN_REQUIRED_SCALE: u8 = 6;
 
 // Re-export Eip712Domain for use in client module
 pub use alloy::sol_types::Eip712Domain;
@@ -62,6 +79,7 @@ impl TransferWithAuthorization {
 }
 
 /// EIP-3009 based assets/tokens
+#[derive(Clone)]
 pub struct EvmAsset {
     name: String,
     version: String,
@@ -70,6 +88,14 @@ pub struct EvmAsset {
     extra: Value,
 }
 
+/// The token asset to register, see `EvmScheme::asset`
+#[derive(Clone, Debug)]
+pub struct EvmAssetSpec {
+    pub address: String,
+    pub decimals: Option<u8>,
+    pub skip_probe: bool,
+}
+
 /// EIP-8004 agent registry infomation
 #[derive(Clone, Debug)]
 pub struct Evm8004Registry {
@@ -77,20 +103,31 @@ pub struct Evm8004Registry {
     pub identity: String,
 }
 
+#[derive(Clone)]
 struct InnerEvm8004Registry {
     pub agent_id: U256,
     pub identity_registry: Address,
 }
 
 /// Evm-based scheme
+#[derive(Clone)]
 pub struct EvmScheme {
     chain_id: u64,
     scheme: String,
     network: String,
     rpc: Url,
+    http: Client,
     signer: PrivateKeySigner,
     assets: HashMap<Address, EvmAsset>,
     agent: Option<InnerEvm8004Registry>,
+    max_schedule: u64,
+    max_assets: usize,
+    max_required_tokens: u64,
+    gas: GasStrategy,
+    max_gas_price: Option<u128>,
+    nonce_lock: Arc<Mutex<()>>,
+    submitter: Arc<dyn Submitter>,
+    custom_submitter: bool,
 }
 
 impl EvmScheme {
@@ -102,9 +139,12 @@ impl EvmScheme {
         agent: Option<Evm8004Registry>,
     ) -> Result<Self> {
         let rpc: Url = url.parse()?;
-        let signer = signer.parse()?;
+        let signer: PrivateKeySigner = signer.parse()?;
+        let http = Client::builder()
+            .timeout(Duration::from_secs(DEFAULT_RPC_TIMEOUT))
+            .build()?;
 
-        let provider = ProviderBuilder::new().connect_http(rpc.clone());
+        let provider = ProviderBuilder::new().connect_reqwest(http.clone(), rpc.clone());
         let chain_id = provider.get_chain_id().await?;
 
         let agent = if let Some(agent) = agent {
@@ -117,21 +157,116 @@ impl EvmScheme {
             None
         };
 
+        let nonce_lock = Arc::new(Mutex::new(()));
+        let submitter = Arc::new(
+            DirectSubmitter::new(rpc.clone(), http.clone(), signer.clone())
+                .nonce_lock(nonce_lock.clone()),
+        );
+
         Ok(Self {
             chain_id,
+            submitter,
+            custom_submitter: false,
             rpc,
+            http,
             signer,
             agent,
             scheme: SCHEME.to_owned(),
             network: network.to_owned(),
             assets: HashMap::new(),
+            max_schedule: 0,
+            max_assets: DEFAULT_MAX_ASSETS,
+            max_required_tokens: DEFAULT_MAX_REQUIRED_TOKENS,
+            gas: GasStrategy::Auto,
+            max_gas_price: None,
+            nonce_lock,
         })
     }
 
+    /// Accept future-dated authorizations (validAfter in the future) up to `max_delay` seconds,
+    /// their settlement is pending and the facilitator settles them once valid. 0 is disabled (default)
+    pub fn schedule(&mut self, max_delay: u64) {
+        self.max_schedule = max_delay;
+    }
+
+    /// Limit the registered assets, default is DEFAULT_MAX_ASSETS
+    pub fn max_assets(&mut self, max: usize) {
+        self.max_assets = max;
+    }
+
+    /// Limit the required amount in whole tokens, a larger one is rejected as a wrong decimals
+    /// scale, default is DEFAULT_MAX_REQUIRED_TOKENS
+    pub fn max_required_tokens(&mut self, max: u64) {
+        self.max_required_tokens = max;
+    }
+
+    /// Submit the settlement transactions by the submitter, default is broadcast by the signer
+    pub fn submitter<T: Submitter + 'static>(&mut self, submitter: T) {
+        self.submitter = Arc::new(submitter);
+        self.custom_submitter = true;
+    }
+
+    /// Broadcast the settlement transactions by the signer with the gas strategy,
+    /// a relayer or custom submitter is kept and prices its own gas
+    pub fn gas(&mut self, gas: GasStrategy) {
+        self.gas = gas;
+        self.direct();
+    }
+
+    /// Share the signer account lock with the other senders of the same account on this chain
+    /// (e.g. the deposit settlements), so the concurrent transactions never collide on the nonce,
+    /// a relayer or custom submitter is kept
+    pub fn nonce_lock(&mut self, lock: Arc<Mutex<()>>) {
+        self.nonce_lock = lock;
+        self.direct();
+    }
+
+    /// The ceiling of the settlement gas price (wei) when retrying the underpriced transactions,
+    /// a relayer or custom submitter is kept
+    pub fn max_gas_price(&mut self, max_gas_price: Option<u128>) {
+        self.max_gas_price = max_gas_price;
+        self.direct();
+    }
+
+    // rebuild the default submitter with the settings, never replace the relayer or custom one
+    fn direct(&mut self) {
+        if self.custom_submitter {
+            return;
+        }
+        self.submitter = Arc::new(
+            DirectSubmitter::new(self.rpc.clone(), self.http.clone(), self.signer.clone())
+                .gas(self.gas)
+                .max_gas_price(self.max_gas_price)
+                .nonce_lock(self.nonce_lock.clone()),
+        );
+    }
+
+    /// Every rpc request will fail after `timeout` seconds, default is DEFAULT_RPC_TIMEOUT,
+    /// set it before the relayer which shares the rpc client
+    pub fn timeout(&mut self, timeout: u64) -> Result<()> {
+        self.http = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+        self.direct();
+        Ok(())
+    }
+
+    /// Submit the settlement transactions by a gasless relayer, so the signer needs no gas
+    pub fn relayer(&mut self, url: &str) {
+        self.submitter(RelayerSubmitter::new(
+            url,
+            self.chain_id,
+            self.rpc.clone(),
+            self.http.clone(),
+        ));
+    }
+
     /// Add a new EIP-3009 token asset to the scheme
     ///
     /// # Arguments
     /// * `addr` - The token contract address
+    /// * `decimals` - The pre-known token decimals, read from the contract if None
+    /// * `skip_probe` - Trust the token supports EIP-3009, skip the `authorizationState` probe
     ///
     /// # Returns
     /// * `Ok(())` if the token is valid and supports EIP-3009
@@ -140,25 +275,43 @@ impl EvmScheme {
     /// # Note
     /// This function automatically reads the token name and version from the contract
     /// to ensure they match the contract's DOMAIN_SEPARATOR for EIP-712 signing
-    pub async fn asset(&mut self, addr: &str) -> Result<()> {
+    pub async fn asset(
+        &mut self,
+        addr: &str,
+        decimals: Option<u8>,
+        skip_probe: bool,
+    ) -> Result<()> {
         let token_address: Address = addr.parse()?;
+        if !self.assets.contains_key(&token_address) && self.assets.len() >= self.max_assets {
+            return Err(anyhow::anyhow!(
+                "{}: more than max assets {}",
+                self.network,
+                self.max_assets
+            ));
+        }
 
         // Create provider and contract instance
-        let provider = ProviderBuilder::new().connect_http(self.rpc.clone());
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
 
         // Verify the contract has the required EIP-3009 functions by calling view functions
         let contract = Eip3009Token::new(token_address, &provider);
-        let decimal = contract.decimals().call().await?;
+        let decimal = match decimals {
+            Some(decimal) => decimal,
+            None => contract.decimals().call().await?,
+        };
 
         // Verify EIP-3009 support by checking if authorizationState exists
         // We test with a random address and nonce - if the function doesn't exist, it will fail
-        contract
-            .authorizationState(Address::ZERO, B256::ZERO)
-            .call()
-            .await?;
+        if !skip_probe {
+            contract
+                .authorizationState(Address::ZERO, B256::ZERO)
+                .call()
+                .await?;
+        }
 
         // Read the contract's actual name, version, and DOMAIN_SEPARATOR
         let name = contract.name().call().await?;
+        let symbol = contract.symbol().call().await.unwrap_or_default();
         let version = contract.version().call().await?;
         let contract_domain_separator = contract.DOMAIN_SEPARATOR().call().await?;
 
@@ -183,6 +336,7 @@ impl EvmScheme {
             "name": name,
             "version": version,
             "chainId": self.chain_id,
+            "symbol": symbol,
         });
         let asset = EvmAsset {
             name: name.to_owned(),
@@ -197,7 +351,31 @@ impl EvmScheme {
         Ok(())
     }
 
-    async fn handle_verify(&self, req: &VerifyRequest) -> Result<(), Error> {
+    /// Register a batch of assets, every asset is registered fully or not at all,
+    /// so a failed one never leaves a partial asset in the scheme.
+    ///
+    /// # Returns
+    /// The registered asset addresses, and the failed ones with the error for logging or retry
+    pub async fn try_assets(
+        &mut self,
+        specs: &[EvmAssetSpec],
+    ) -> (Vec<String>, Vec<(String, anyhow::Error)>) {
+        let mut registered = vec![];
+        let mut failures = vec![];
+        for spec in specs {
+            match self
+                .asset(&spec.address, spec.decimals, spec.skip_probe)
+                .await
+            {
+                Ok(()) => registered.push(spec.address.clone()),
+                Err(err) => failures.push((spec.address.clone(), err)),
+            }
+        }
+
+        (registered, failures)
+    }
+
+    async fn handle_verify(&self, req: &VerifyRequest) -> Result<Option<u64>, Error> {
         // 1. signature validation
         let token: Address = req
             .payment_requirements
@@ -216,8 +394,22 @@ impl EvmScheme {
             .get(&token)
             .ok_or(Error::InvalidPaymentRequirements)?;
 
+        // the required amount must be in the scale of the asset decimals, e.g. an 18 decimals
+        // amount of a 6 decimals token is absurdly large, and the reverse is almost zero
+        let required_amount: U256 = req
+            .payment_requirements
+            .max_amount_required
+            .parse()
+            .map_err(|_| Error::InvalidPaymentRequirements)?;
+        if !amount_in_scale(required_amount, asset.decimal, self.max_required_tokens) {
+            return Err(Error::InvalidPaymentRequirements);
+        }
+
         let auth = &req.payment_payload.payload.authorization;
 
+        // a malformed nonce is the payload error, not a signature error
+        let nonce: B256 = auth.nonce.parse().map_err(|_| Error::InvalidPayload)?;
+
         // Verify the signature
         if verify_authorization(&asset.domain, auth, &sign).is_err() {
             return Err(Error::InvalidExactEvmPayloadSignature);
@@ -227,22 +419,15 @@ impl EvmScheme {
         let from: Address = auth.from.parse().map_err(|_| Error::InvalidPayload)?;
 
         // Create contract instance for balance check
-        let provider = ProviderBuilder::new().connect_http(self.rpc.clone());
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
         let contract = Eip3009Token::new(token, provider);
 
-        let balance = contract
-            .balanceOf(from)
-            .call()
+        let balance = timed("balance_of", contract.balanceOf(from).call())
             .await
             .map_err(|_| Error::UnexpectedVerifyError)?;
 
         // 3. amount validation
         let value: U256 = auth.value.parse().map_err(|_| Error::InvalidPayload)?;
-        let required_amount: U256 = req
-            .payment_requirements
-            .max_amount_required
-            .parse()
-            .map_err(|_| Error::InvalidPaymentRequirements)?;
 
         if balance < value {
             return Err(Error::InsufficientFunds);
@@ -267,9 +452,15 @@ impl EvmScheme {
             .parse()
             .map_err(|_| Error::InvalidPayload)?;
 
-        if now < valid_after {
-            return Err(Error::InvalidExactEvmPayloadAuthorizationValidAfter);
-        }
+        // future-dated authorization is accepted and scheduled if enabled
+        let scheduled = if now < valid_after {
+            if valid_after - now > self.max_schedule {
+                return Err(Error::InvalidExactEvmPayloadAuthorizationValidAfter);
+            }
+            Some(valid_after)
+        } else {
+            None
+        };
 
         if now > valid_before {
             return Err(Error::InvalidExactEvmPayloadAuthorizationValidBefore);
@@ -288,19 +479,47 @@ impl EvmScheme {
         }
 
         // 6. check authorization state (nonce not used)
-        let nonce: B256 = auth.nonce.parse().map_err(|_| Error::InvalidPayload)?;
-
-        let is_used = contract
-            .authorizationState(from, nonce)
-            .call()
-            .await
-            .map_err(|_| Error::UnexpectedVerifyError)?;
+        let is_used = timed(
+            "authorization_state",
+            contract.authorizationState(from, nonce).call(),
+        )
+        .await
+        .map_err(|_| Error::UnexpectedVerifyError)?;
 
         if is_used {
             return Err(Error::InvalidExactEvmPayloadSignature);
         }
 
-        Ok(())
+        Ok(scheduled)
+    }
+
+    /// Check the future-dated authorization can be scheduled, the facilitator saves it and
+    /// settles it when validAfter passes. Return None if it is valid already.
+    fn handle_schedule(&self, req: &VerifyRequest) -> Result<Option<u64>, Error> {
+        if self.max_schedule == 0 {
+            return Ok(None);
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| Error::UnexpectedSettleError)?
+            .as_secs();
+        let valid_after: u64 = req
+            .payment_payload
+            .payload
+            .authorization
+            .valid_after
+            .parse()
+            .map_err(|_| Error::InvalidPayload)?;
+
+        if now >= valid_after {
+            return Ok(None);
+        }
+        if valid_after - now > self.max_schedule {
+            return Err(Error::InvalidExactEvmPayloadAuthorizationValidAfter);
+        }
+
+        Ok(Some(valid_after))
     }
 
     async fn handle_settle(
@@ -346,10 +565,8 @@ impl EvmScheme {
         let r: B256 = signature.r().into();
         let s: B256 = signature.s().into();
 
-        // Create contract instance for settlement
-        let provider = ProviderBuilder::new()
-            .wallet(self.signer.clone())
-            .connect_http(self.rpc.clone());
+        // Create contract instance for settlement call data
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
         let contract = Eip3009Token::new(token, provider);
 
         // Call transferWithAuthorization (using _0 suffix for overloaded function)
@@ -365,17 +582,15 @@ impl EvmScheme {
             s,
         );
 
-        // Send the transaction
-        let pending_tx = call
-            .send()
+        // Submit the transaction and wait for it to be confirmed
+        let tx = self
+            .submitter
+            .submit(token, call.calldata().clone())
             .await
-            .map_err(|_| Error::InvalidTransactionState)?;
-
-        // Wait for the transaction to be confirmed
-        let receipt = pending_tx
-            .get_receipt()
-            .await
-            .map_err(|_| Error::InvalidTransactionState)?;
+            .map_err(|e| {
+                tracing::error!("Settle submit: {:?}", e);
+                Error::InvalidTransactionState
+            })?;
 
         let feedback_auth = match (&self.agent, req.payment_payload.payload.feedback_index) {
             (Some(agent), Some(index)) => {
@@ -403,7 +618,7 @@ impl EvmScheme {
         };
 
         // Return the transaction hash
-        Ok((format!("{:?}", receipt.transaction_hash), feedback_auth))
+        Ok((format!("{:?}", tx), feedback_auth))
     }
 }
 
@@ -476,10 +691,11 @@ impl PaymentScheme for EvmScheme {
     /// 6. Transaction Simulation: Simulate the transferWithAuthorization transaction to ensure it would succeed
     async fn verify(&self, req: &VerifyRequest) -> VerifyResponse {
         match self.handle_verify(req).await {
-            Ok(_) => VerifyResponse {
+            Ok(scheduled_at) => VerifyResponse {
                 is_valid: true,
                 payer: req.payment_payload.payload.authorization.from.clone(),
                 invalid_reason: None,
+                scheduled_at,
             },
             Err(error) => error.verify(&req.payment_payload),
         }
@@ -489,20 +705,71 @@ impl PaymentScheme for EvmScheme {
     /// function on the ERC-20 contract with the signature and authorization
     /// parameters provided in the payment payload.
     async fn settle(&self, req: &VerifyRequest) -> SettlementResponse {
+        match self.handle_schedule(req) {
+            Ok(Some(scheduled_at)) => {
+                return SettlementResponse {
+                    success: false,
+                    pending: true,
+                    error_reason: None,
+                    transaction: "".to_owned(),
+                    network: req.payment_payload.network.clone(),
+                    payer: req.payment_payload.payload.authorization.from.clone(),
+                    feedback_auth: None,
+                    scheduled_at: Some(scheduled_at),
+                    receipt: None,
+                };
+            }
+            Ok(None) => {}
+            Err(error) => return error.settle(&req.payment_payload),
+        }
+
         match self.handle_settle(req).await {
-            Ok((tx_hash, feedback_auth)) => SettlementResponse {
-                success: true,
-                error_reason: None,
-                transaction: tx_hash,
-                network: req.payment_payload.network.clone(),
-                payer: req.payment_payload.payload.authorization.from.clone(),
-                feedback_auth,
-            },
+            Ok((tx_hash, feedback_auth)) => {
+                let auth = &req.payment_payload.payload.authorization;
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                let receipt = SettlementReceipt::sign(
+                    &auth.from,
+                    &auth.to,
+                    &req.payment_requirements.asset,
+                    &auth.value,
+                    &req.payment_payload.network,
+                    &tx_hash,
+                    now,
+                    &self.signer,
+                )
+                .ok();
+
+                SettlementResponse {
+                    success: true,
+                    pending: false,
+                    error_reason: None,
+                    transaction: tx_hash,
+                    network: req.payment_payload.network.clone(),
+                    payer: auth.from.clone(),
+                    feedback_auth,
+                    scheduled_at: None,
+                    receipt,
+                }
+            }
             Err(error) => error.settle(&req.payment_payload),
         }
     }
 }
 
+/// Time a rpc call, log the duration and record it to the per-operation histogram,
+/// also used by the scanner, so all rpc timings are in one histogram
+pub async fn timed<F: IntoFuture>(op: &'static str, fut: F) -> F::Output {
+    let start = Instant::now();
+    let res = fut.await;
+    let elapsed = start.elapsed();
+    tracing::debug!("RPC {op}: {:?}", elapsed);
+    metrics::histogram!("zeropay_rpc_duration_seconds", "op" => op).record(elapsed.as_secs_f64());
+    res
+}
+
 /// Use standard EIP712 signature defined in:  https://eips.ethereum.org/EIPS/eip-3009
 /// const data = {
 ///     types: {
@@ -604,6 +871,15 @@ pub fn verify_authorization(
     }
 }
 
+/// Check the atomic amount is within 10^-6 and the max whole tokens of the decimals,
+/// a zero amount is never valid
+fn amount_in_scale(amount: U256, decimal: u8, max_tokens: u64) -> bool {
+    let ten = U256::from(10);
+    let min = ten.pow(U256::from(decimal.saturating_sub(MIN_REQUIRED_SCALE)));
+    let max = U256::from(max_tokens) * ten.pow(U256::from(decimal));
+    amount >= min && amount <= max
+}
+
 /// Convert a decimal price string to U256 with the specified number of decimals
 ///
 /// # Arguments
@@ -653,3 +929,99 @@ fn price_to_u256(s: &str, decimal: u8) -> U256 {
     // Combine: int_part * 10^decimal + frac_value
     int_part * U256::from(10).pow(U256::from(decimal)) + frac_value
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_in_scale() {
+        let max = DEFAULT_MAX_REQUIRED_TOKENS;
+
+        // 6 decimals token, e.g. USDC
+        assert!(amount_in_scale(U256::from(1_000_000), 6, max));
+        assert!(amount_in_scale(U256::from(1), 6, max));
+        assert!(!amount_in_scale(U256::ZERO, 6, max));
+
+        // 1 token computed with 18 decimals for the 6 decimals token is 10^12 tokens
+        let wrong_scale = U256::from(10).pow(U256::from(18));
+        assert!(!amount_in_scale(wrong_scale, 6, max));
+        assert!(amount_in_scale(wrong_scale, 18, max));
+
+        // the configured max
+        let ten_tokens = U256::from(10_000_000);
+        assert!(amount_in_scale(ten_tokens, 6, 10));
+        assert!(!amount_in_scale(ten_tokens + U256::from(1), 6, 10));
+    }
+
+    struct MockSubmitter;
+
+    #[async_trait]
+    impl Submitter for MockSubmitter {
+        async fn submit(&self, _to: Address, _data: Bytes) -> Result<B256> {
+            Ok(B256::repeat_byte(1))
+        }
+    }
+
+    fn scheme() -> EvmScheme {
+        let rpc: Url = "http://127.0.0.1:1".parse().unwrap();
+        let http = Client::new();
+        let signer = PrivateKeySigner::random();
+        let nonce_lock = Arc::new(Mutex::new(()));
+        EvmScheme {
+            chain_id: 1,
+            scheme: SCHEME.to_owned(),
+            network: "ethereum".to_owned(),
+            submitter: Arc::new(DirectSubmitter::new(
+                rpc.clone(),
+                http.clone(),
+                signer.clone(),
+            )),
+            custom_submitter: false,
+            rpc,
+            http,
+            signer,
+            assets: HashMap::new(),
+            agent: None,
+            max_schedule: 0,
+            max_assets: DEFAULT_MAX_ASSETS,
+            max_required_tokens: DEFAULT_MAX_REQUIRED_TOKENS,
+            gas: GasStrategy::Auto,
+            max_gas_price: None,
+            nonce_lock,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setters_keep_custom_submitter() {
+        let mut scheme = scheme();
+        scheme.submitter(MockSubmitter);
+        scheme.gas(GasStrategy::Legacy);
+        scheme.nonce_lock(Arc::new(Mutex::new(())));
+        scheme.max_gas_price(Some(100));
+
+        let hash = scheme
+            .submitter
+            .submit(Address::ZERO, Bytes::new())
+            .await
+            .unwrap();
+        assert_eq!(hash, B256::repeat_byte(1));
+    }
+
+    #[tokio::test]
+    async fn test_setters_rebuild_direct_submitter() {
+        let mut scheme = scheme();
+        scheme.gas(GasStrategy::Legacy);
+        scheme.max_gas_price(Some(100));
+
+        // the direct submitter sends to the unreachable rpc
+        assert!(!scheme.custom_submitter);
+        assert!(
+            scheme
+                .submitter
+                .submit(Address::ZERO, Bytes::new())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,2 +1,4 @@
 pub mod evm;
+pub mod nft;
 pub mod sol;
+pub mod submitter;
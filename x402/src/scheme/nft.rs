@@ -0,0 +1,499 @@
+use super::evm::{create_eip712_domain, timed};
+use crate::{
+    Authorization, Error, NFT_SCHEME, Payee, PaymentRequirements, PaymentScheme,
+    SettlementResponse, VerifyRequest, VerifyResponse,
+};
+use alloy::{
+    primitives::{Address, B256, U256},
+    providers::{Provider, ProviderBuilder},
+    signers::{Signature, SignerSync, local::PrivateKeySigner},
+    sol,
+    sol_types::{Eip712Domain, SolStruct},
+    transports::http::reqwest::{Client, Url},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// EIP-712 domain name of the ownership challenge
+pub const NFT_DOMAIN_NAME: &str = "x402 NFT Gate";
+
+/// EIP-712 domain version of the ownership challenge
+pub const NFT_DOMAIN_VERSION: &str = "1";
+
+sol! {
+    #[sol(rpc)]
+    interface Erc721 {
+        function balanceOf(address owner) external view returns (uint256);
+        function ownerOf(uint256 tokenId) external view returns (address);
+    }
+
+    #[sol(rpc)]
+    interface Erc1155 {
+        function balanceOf(address account, uint256 id) external view returns (uint256);
+    }
+}
+
+// Ownership challenge struct for EIP-712 signing, the authorization value is the token id,
+// and it is bound to the requirements resource url
+sol! {
+    #[derive(Debug)]
+    struct OwnershipChallenge {
+        address owner;
+        address payTo;
+        string resource;
+        address collection;
+        uint256 tokenId;
+        uint256 validAfter;
+        uint256 validBefore;
+        bytes32 nonce;
+    }
+}
+
+impl OwnershipChallenge {
+    pub fn from(
+        auth: &Authorization,
+        collection: Address,
+        resource: &str,
+    ) -> Result<OwnershipChallenge> {
+        Ok(OwnershipChallenge {
+            owner: auth.from.parse()?,
+            payTo: auth.to.parse()?,
+            resource: resource.to_owned(),
+            collection,
+            tokenId: auth.value.parse()?,
+            validAfter: auth.valid_after.parse()?,
+            validBefore: auth.valid_before.parse()?,
+            nonce: auth.nonce.parse()?,
+        })
+    }
+}
+
+/// The NFT token standard of a collection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NftStandard {
+    Erc721,
+    Erc1155,
+}
+
+impl NftStandard {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NftStandard::Erc721 => "erc721",
+            NftStandard::Erc1155 => "erc1155",
+        }
+    }
+}
+
+// The challenges already settled, a challenge is used once until its validBefore
+#[derive(Default)]
+struct UsedChallenges {
+    used: Mutex<HashMap<(Address, B256), u64>>,
+}
+
+impl UsedChallenges {
+    fn is_used(&self, owner: Address, nonce: B256) -> bool {
+        self.used
+            .lock()
+            .map(|used| used.contains_key(&(owner, nonce)))
+            .unwrap_or(true)
+    }
+
+    // Mark the challenge used, false if it is used already. The expired ones are dropped,
+    // they are rejected by the time window
+    fn consume(&self, owner: Address, nonce: B256, valid_before: u64, now: u64) -> bool {
+        let Ok(mut used) = self.used.lock() else {
+            return false;
+        };
+        used.retain(|_, before| *before >= now);
+        if used.contains_key(&(owner, nonce)) {
+            return false;
+        }
+        used.insert((owner, nonce), valid_before);
+        true
+    }
+}
+
+#[derive(Clone)]
+struct NftCollection {
+    standard: NftStandard,
+    token_id: Option<U256>,
+    domain: Eip712Domain,
+}
+
+/// Evm-based token-gating scheme, the payer proves holding the NFT by
+/// signing an ownership challenge, no token is transferred
+#[derive(Clone)]
+pub struct NftScheme {
+    chain_id: u64,
+    scheme: String,
+    network: String,
+    rpc: Url,
+    http: Client,
+    collections: HashMap<Address, NftCollection>,
+    used: Arc<UsedChallenges>,
+}
+
+impl NftScheme {
+    /// Build the nft scheme, every rpc request will fail after `timeout` seconds
+    pub async fn new(url: &str, network: &str, timeout: u64) -> Result<Self> {
+        let rpc: Url = url.parse()?;
+        let http = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()?;
+
+        let provider = ProviderBuilder::new().connect_reqwest(http.clone(), rpc.clone());
+        let chain_id = provider.get_chain_id().await?;
+
+        Ok(Self {
+            chain_id,
+            rpc,
+            http,
+            scheme: NFT_SCHEME.to_owned(),
+            network: network.to_owned(),
+            collections: HashMap::new(),
+            used: Arc::new(UsedChallenges::default()),
+        })
+    }
+
+    /// Require holding the NFT collection
+    ///
+    /// # Arguments
+    /// * `addr` - The collection contract address
+    /// * `standard` - ERC-721 or ERC-1155
+    /// * `token_id` - The required token id, any token of an ERC-721 collection if None.
+    ///   It is required for ERC-1155
+    pub fn collection(
+        &mut self,
+        addr: &str,
+        standard: NftStandard,
+        token_id: Option<U256>,
+    ) -> Result<()> {
+        let collection: Address = addr.parse()?;
+        if standard == NftStandard::Erc1155 && token_id.is_none() {
+            return Err(anyhow::anyhow!("{}: ERC-1155 requires the token id", addr));
+        }
+
+        let domain = create_eip712_domain(
+            NFT_DOMAIN_NAME.to_owned(),
+            NFT_DOMAIN_VERSION.to_owned(),
+            self.chain_id,
+            collection,
+        );
+        self.collections.insert(
+            collection,
+            NftCollection {
+                standard,
+                token_id,
+                domain,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn handle_verify(&self, req: &VerifyRequest) -> Result<(), Error> {
+        let requirements = &req.payment_requirements;
+        let collection: Address = req
+            .payment_requirements
+            .asset
+            .parse()
+            .map_err(|_| Error::InvalidPaymentRequirements)?;
+        let nft = self
+            .collections
+            .get(&collection)
+            .ok_or(Error::InvalidPaymentRequirements)?;
+        let auth = &req.payment_payload.payload.authorization;
+
+        // 1. time window check
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| Error::UnexpectedVerifyError)?
+            .as_secs();
+        let valid_after: u64 = auth
+            .valid_after
+            .parse()
+            .map_err(|_| Error::InvalidPayload)?;
+        let valid_before: u64 = auth
+            .valid_before
+            .parse()
+            .map_err(|_| Error::InvalidPayload)?;
+        if now < valid_after {
+            return Err(Error::InvalidExactEvmPayloadAuthorizationValidAfter);
+        }
+        if now > valid_before {
+            return Err(Error::InvalidExactEvmPayloadAuthorizationValidBefore);
+        }
+        // a long lived challenge is a reusable pass, bound it by the requirements timeout
+        if valid_before - now > requirements.max_timeout_seconds.max(0) as u64 {
+            return Err(Error::InvalidExactEvmPayloadAuthorizationValidBefore);
+        }
+
+        // 2. parameter matching
+        let to: Address = auth.to.parse().map_err(|_| Error::InvalidPayload)?;
+        let expected_to: Address = req
+            .payment_requirements
+            .pay_to
+            .parse()
+            .map_err(|_| Error::InvalidPaymentRequirements)?;
+        if to != expected_to {
+            return Err(Error::InvalidExactEvmPayloadRecipientMismatch);
+        }
+
+        let token_id: U256 = auth.value.parse().map_err(|_| Error::InvalidPayload)?;
+        if nft.token_id.is_some_and(|id| id != token_id) {
+            return Err(Error::InvalidExactEvmPayloadAuthorizationValue);
+        }
+
+        // 3. signature validation
+        let signature: Signature = req
+            .payment_payload
+            .payload
+            .signature
+            .parse()
+            .map_err(|_| Error::InvalidExactEvmPayloadSignature)?;
+        if verify_ownership_challenge(&nft.domain, auth, &requirements.resource, &signature)
+            .is_err()
+        {
+            return Err(Error::InvalidExactEvmPayloadSignature);
+        }
+        let owner: Address = auth.from.parse().map_err(|_| Error::InvalidPayload)?;
+        let nonce: B256 = auth.nonce.parse().map_err(|_| Error::InvalidPayload)?;
+        if self.used.is_used(owner, nonce) {
+            return Err(Error::InvalidExactEvmPayloadSignature);
+        }
+
+        // 4. ownership verification
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
+        let holding = match (nft.standard, nft.token_id) {
+            (NftStandard::Erc721, Some(id)) => {
+                let contract = Erc721::new(collection, provider);
+                timed("owner_of", contract.ownerOf(id).call())
+                    .await
+                    .map_err(|_| Error::UnexpectedVerifyError)?
+                    == owner
+            }
+            (NftStandard::Erc721, None) => {
+                let contract = Erc721::new(collection, provider);
+                timed("balance_of", contract.balanceOf(owner).call())
+                    .await
+                    .map_err(|_| Error::UnexpectedVerifyError)?
+                    > U256::ZERO
+            }
+            (NftStandard::Erc1155, id) => {
+                let contract = Erc1155::new(collection, provider);
+                timed(
+                    "balance_of",
+                    contract.balanceOf(owner, id.unwrap_or_default()).call(),
+                )
+                .await
+                .map_err(|_| Error::UnexpectedVerifyError)?
+                    > U256::ZERO
+            }
+        };
+        if !holding {
+            return Err(Error::InsufficientFunds);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PaymentScheme for NftScheme {
+    /// The scheme of this payment scheme
+    fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// The network of this payment scheme
+    fn network(&self) -> &str {
+        &self.network
+    }
+
+    /// Create requirements of holding every registered collection, the price is ignored
+    fn create(&self, _price: &str, payee: Payee) -> Vec<PaymentRequirements> {
+        let Some(pay_to) = payee.evm else {
+            return vec![];
+        };
+
+        self.collections
+            .iter()
+            .map(|(collection, nft)| PaymentRequirements {
+                scheme: self.scheme.clone(),
+                network: self.network.clone(),
+                max_amount_required: "1".to_owned(),
+                asset: collection.to_checksum(None),
+                pay_to: pay_to.clone(),
+                resource: String::new(),
+                description: format!("Holding the {} token", nft.standard.as_str()),
+                mime_type: None,
+                output_schema: None,
+                max_timeout_seconds: 300,
+                extra: Some(json!({
+                    "standard": nft.standard.as_str(),
+                    "tokenId": nft.token_id.map(|id| id.to_string()),
+                    "name": NFT_DOMAIN_NAME,
+                    "version": NFT_DOMAIN_VERSION,
+                    "chainId": self.chain_id,
+                })),
+            })
+            .collect()
+    }
+
+    /// The facilitator performs the following verification steps:
+    /// 1. Time Window Check: Verify the challenge is within its valid time range
+    /// 2. Parameter Matching: Confirm the resource and token id match the requirements
+    /// 3. Signature Validation: Verify the EIP-712 challenge is signed by the owner
+    /// 4. Ownership Verification: Confirm the owner holds the token onchain
+    async fn verify(&self, req: &VerifyRequest) -> VerifyResponse {
+        match self.handle_verify(req).await {
+            Ok(()) => VerifyResponse {
+                is_valid: true,
+                payer: req.payment_payload.payload.authorization.from.clone(),
+                invalid_reason: None,
+                scheduled_at: None,
+            },
+            Err(error) => error.verify(&req.payment_payload),
+        }
+    }
+
+    /// Nothing is transferred, the settlement verifies the ownership again and uses the
+    /// challenge, and the transaction is empty
+    async fn settle(&self, req: &VerifyRequest) -> SettlementResponse {
+        let res = self.verify(req).await;
+        if !res.is_valid {
+            return res.to_settle(&req.payment_payload.network, "");
+        }
+
+        let auth = &req.payment_payload.payload.authorization;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let used = match (
+            auth.from.parse::<Address>(),
+            auth.nonce.parse::<B256>(),
+            auth.valid_before.parse::<u64>(),
+        ) {
+            (Ok(owner), Ok(nonce), Ok(before)) => self.used.consume(owner, nonce, before, now),
+            _ => false,
+        };
+        if !used {
+            return Error::InvalidExactEvmPayloadSignature.settle(&req.payment_payload);
+        }
+
+        res.to_settle(&req.payment_payload.network, "")
+    }
+}
+
+/// Sign an ownership challenge using EIP-712, the authorization `from` is the owner,
+/// `to` is the resource pay_to, and `value` is the token id (0 if any token).
+/// The `resource` is the requirements resource url
+pub fn sign_ownership_challenge(
+    domain: &Eip712Domain,
+    auth: &Authorization,
+    resource: &str,
+    signer: &PrivateKeySigner,
+) -> Result<Signature> {
+    let collection = domain
+        .verifying_contract
+        .ok_or(anyhow::anyhow!("No verifying contract"))?;
+    let challenge = OwnershipChallenge::from(auth, collection, resource)?;
+    let signing_hash = challenge.eip712_signing_hash(domain);
+    let signature = signer.sign_hash_sync(&signing_hash)?;
+    Ok(signature)
+}
+
+/// Verify an EIP-712 ownership challenge is signed by the owner
+pub fn verify_ownership_challenge(
+    domain: &Eip712Domain,
+    auth: &Authorization,
+    resource: &str,
+    signature: &Signature,
+) -> Result<()> {
+    let owner: Address = auth.from.parse()?;
+    let collection = domain
+        .verifying_contract
+        .ok_or(anyhow::anyhow!("No verifying contract"))?;
+    let challenge = OwnershipChallenge::from(auth, collection, resource)?;
+    let signing_hash = challenge.eip712_signing_hash(domain);
+    let recover = signature.recover_address_from_prehash(&signing_hash)?;
+
+    if recover == owner {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Invalid recover and owner"))
+    }
+}
+
+/// Build the EIP-712 domain of the ownership challenge from the requirements
+pub fn ownership_domain(requirements: &PaymentRequirements) -> Result<Eip712Domain> {
+    let collection: Address = requirements.asset.parse()?;
+    let chain_id = requirements
+        .extra
+        .as_ref()
+        .and_then(|e| e["chainId"].as_u64())
+        .ok_or(anyhow::anyhow!("No chainId in requirements"))?;
+    Ok(create_eip712_domain(
+        NFT_DOMAIN_NAME.to_owned(),
+        NFT_DOMAIN_VERSION.to_owned(),
+        chain_id,
+        collection,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(owner: Address) -> Authorization {
+        Authorization {
+            from: owner.to_checksum(None),
+            to: "0x0000000000000000000000000000000000000002".to_owned(),
+            value: "7".to_owned(),
+            valid_after: "0".to_owned(),
+            valid_before: "9999999999".to_owned(),
+            nonce: format!("0x{}", "11".repeat(32)),
+        }
+    }
+
+    #[test]
+    fn test_challenge_bound_to_resource() {
+        let signer = PrivateKeySigner::random();
+        let domain = create_eip712_domain(
+            NFT_DOMAIN_NAME.to_owned(),
+            NFT_DOMAIN_VERSION.to_owned(),
+            8453,
+            Address::repeat_byte(1),
+        );
+        let auth = challenge(signer.address());
+        let resource = "https://api.example.com/premium";
+        let signature = sign_ownership_challenge(&domain, &auth, resource, &signer).unwrap();
+
+        assert!(verify_ownership_challenge(&domain, &auth, resource, &signature).is_ok());
+        assert!(
+            verify_ownership_challenge(&domain, &auth, "https://api.example.com/other", &signature)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_challenge_used_once() {
+        let used = UsedChallenges::default();
+        let owner = Address::repeat_byte(3);
+        let nonce = B256::repeat_byte(0x11);
+
+        assert!(!used.is_used(owner, nonce));
+        assert!(used.consume(owner, nonce, 200, 100));
+        assert!(used.is_used(owner, nonce));
+        assert!(!used.consume(owner, nonce, 200, 150));
+
+        // expired ones are dropped, the time window rejects them
+        assert!(used.consume(owner, B256::repeat_byte(0x22), 300, 250));
+        assert!(!used.is_used(owner, nonce));
+    }
+}
@@ -0,0 +1,450 @@
+use super::evm::timed;
+use alloy::{
+    eips::BlockNumberOrTag,
+    network::TransactionBuilder,
+    primitives::{Address, B256, Bytes},
+    providers::{PendingTransactionBuilder, Provider, ProviderBuilder},
+    rpc::types::{TransactionReceipt, TransactionRequest},
+    signers::local::PrivateKeySigner,
+    transports::http::reqwest::{Client, Url},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Relayer task polling interval (seconds)
+const RELAYER_POLL_INTERVAL: u64 = 2;
+
+/// Max relayer task polling times, about 2 minutes
+const RELAYER_POLL_TIMES: u64 = 60;
+
+/// Max retries of an underpriced or stuck settlement transaction
+const UNDERPRICED_RETRIES: u32 = 3;
+
+/// Max waiting of the settlement receipt (seconds), a pending one is replaced with a bumped price
+pub const RECEIPT_TIMEOUT: u64 = 90;
+
+/// The retried gas price is at least 12% higher, nodes require 10% to replace a pending one
+const GAS_BUMP_PERCENT: u128 = 112;
+
+/// Submit the settlement call onchain, and wait it confirmed
+#[async_trait]
+pub trait Submitter: Send + Sync {
+    /// Submit the call data to the contract, return the confirmed transaction hash
+    async fn submit(&self, to: Address, data: Bytes) -> Result<B256>;
+}
+
+/// Gas pricing strategy of the settlement transaction
+#[derive(Clone, Copy, Debug, Default)]
+pub enum GasStrategy {
+    /// filled by the provider
+    #[default]
+    Auto,
+    /// gas_price from `eth_gasPrice`
+    Legacy,
+    /// max_fee_per_gas is twice the latest base fee plus the priority fee (tip),
+    /// the tip is the node suggested if not set
+    Eip1559(Option<u128>),
+}
+
+/// Broadcast the transaction by the local signer directly, the signer pays the gas
+pub struct DirectSubmitter {
+    rpc: Url,
+    http: Client,
+    signer: PrivateKeySigner,
+    gas: GasStrategy,
+    max_gas_price: Option<u128>,
+    nonce_lock: Arc<Mutex<()>>,
+}
+
+impl DirectSubmitter {
+    pub fn new(rpc: Url, http: Client, signer: PrivateKeySigner) -> Self {
+        Self {
+            rpc,
+            http,
+            signer,
+            gas: GasStrategy::Auto,
+            max_gas_price: None,
+            nonce_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The ceiling of the gas price (wei), the underpriced transaction is sent again with
+    /// a bumped price up to it, default unlimited
+    pub fn max_gas_price(mut self, max_gas_price: Option<u128>) -> Self {
+        self.max_gas_price = max_gas_price;
+        self
+    }
+
+    /// Set the gas pricing strategy, default is filled by the provider
+    pub fn gas(mut self, gas: GasStrategy) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    /// Share the lock of the signer account with the other senders on the same chain,
+    /// the transactions are sent one by one, so they never get the same nonce
+    pub fn nonce_lock(mut self, lock: Arc<Mutex<()>>) -> Self {
+        self.nonce_lock = lock;
+        self
+    }
+}
+
+impl DirectSubmitter {
+    /// Send the transaction (e.g. a call or a native transfer) and wait it confirmed, an
+    /// underpriced or stuck one is sent again on the same nonce with a bumped price
+    pub async fn send(&self, tx: TransactionRequest) -> Result<B256> {
+        let provider = ProviderBuilder::new()
+            .wallet(self.signer.clone())
+            .connect_reqwest(self.http.clone(), self.rpc.clone());
+        let mut fees = Fees::fetch(self.gas, &provider).await?;
+        if let (Some(fees), Some(ceiling)) = (fees, self.max_gas_price)
+            && fees.max() > ceiling
+        {
+            return Err(anyhow::anyhow!(
+                "Gas price {} is above the max gas price {ceiling}",
+                fees.max()
+            ));
+        }
+
+        // the nonce is the pending transaction count, hold the lock until confirmed,
+        // so a stuck transaction is replaced on the same nonce
+        let _guard = self.nonce_lock.lock().await;
+        let nonce = timed(
+            "get_transaction_count",
+            provider
+                .get_transaction_count(self.signer.address())
+                .pending(),
+        )
+        .await?;
+        let mut sent = vec![];
+        let mut retries = 0;
+        loop {
+            let mut tx = tx.clone().with_nonce(nonce);
+            if let Some(fees) = fees {
+                fees.apply(&mut tx);
+            }
+
+            match timed("send_transaction", provider.send_transaction(tx)).await {
+                Ok(pending_tx) => {
+                    let hash = *pending_tx.tx_hash();
+                    sent.push(hash);
+                    let receipt = tokio::time::timeout(
+                        Duration::from_secs(RECEIPT_TIMEOUT),
+                        timed("get_receipt", pending_tx.get_receipt()),
+                    )
+                    .await;
+                    match receipt {
+                        Ok(receipt) => return succeeded(receipt?),
+                        Err(_) if retries < UNDERPRICED_RETRIES => {
+                            tracing::warn!("Settlement {hash} is pending, replace it");
+                        }
+                        Err(_) => {
+                            return confirmed(&provider, &sent).await?.ok_or(anyhow::anyhow!(
+                                "Settlement {hash} is not confirmed in {RECEIPT_TIMEOUT}s"
+                            ));
+                        }
+                    }
+                }
+                Err(err) if is_underpriced(&err.to_string()) && retries < UNDERPRICED_RETRIES => {
+                    tracing::warn!("Underpriced settlement: {err}");
+                }
+                Err(err) => {
+                    // the replaced transaction is confirmed meanwhile
+                    if let Some(hash) = confirmed(&provider, &sent).await? {
+                        return Ok(hash);
+                    }
+                    return Err(err.into());
+                }
+            }
+
+            // send again with the fresh price, and at least bumped from the last one,
+            // the provider filled price is unknown, so the fresh one is bumped
+            let fresh = Fees::fetch_explicit(self.gas, &provider).await?;
+            let bumped = fees.unwrap_or(fresh).bump(fresh);
+            if self
+                .max_gas_price
+                .is_some_and(|ceiling| bumped.max() > ceiling)
+            {
+                if let Some(hash) = confirmed(&provider, &sent).await? {
+                    return Ok(hash);
+                }
+                return Err(anyhow::anyhow!(
+                    "The bumped gas price is above the max gas price"
+                ));
+            }
+            fees = Some(bumped);
+            retries += 1;
+            tracing::warn!("Settlement retry {retries} with {:?}", bumped);
+        }
+    }
+}
+
+#[async_trait]
+impl Submitter for DirectSubmitter {
+    async fn submit(&self, to: Address, data: Bytes) -> Result<B256> {
+        self.send(TransactionRequest::default().with_to(to).with_input(data))
+            .await
+    }
+}
+
+// the confirmed one of the sent transactions on the same nonce
+async fn confirmed(provider: &impl Provider, sent: &[B256]) -> Result<Option<B256>> {
+    for hash in sent {
+        let receipt = timed(
+            "get_transaction_receipt",
+            provider.get_transaction_receipt(*hash),
+        )
+        .await?;
+        if let Some(receipt) = receipt {
+            return succeeded(receipt).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+// the hash of the confirmed transaction, a reverted one transferred nothing
+fn succeeded(receipt: TransactionReceipt) -> Result<B256> {
+    if receipt.status() {
+        Ok(receipt.transaction_hash)
+    } else {
+        Err(anyhow::anyhow!(
+            "Transaction {} reverted",
+            receipt.transaction_hash
+        ))
+    }
+}
+
+// the node rejected the transaction gas price, it can be sent again with a higher price
+fn is_underpriced(msg: &str) -> bool {
+    let msg = msg.to_lowercase();
+    msg.contains("underpriced")
+        || msg.contains("fee too low")
+        || msg.contains("max fee per gas less than block base fee")
+}
+
+/// The gas price of a transaction
+#[derive(Clone, Copy, Debug)]
+pub enum Fees {
+    Legacy(u128),
+    Eip1559 { max_fee: u128, priority_fee: u128 },
+}
+
+impl Fees {
+    // fetch by the strategy, None is filled by the provider
+    async fn fetch(strategy: GasStrategy, provider: &impl Provider) -> Result<Option<Fees>> {
+        match strategy {
+            GasStrategy::Auto => Ok(None),
+            GasStrategy::Legacy => {
+                let gas_price = timed("get_gas_price", provider.get_gas_price()).await?;
+                Ok(Some(Fees::Legacy(gas_price * 105 / 100))) // add 5%
+            }
+            GasStrategy::Eip1559(tip) => {
+                let latest = timed(
+                    "get_block",
+                    provider.get_block_by_number(BlockNumberOrTag::Latest),
+                )
+                .await?
+                .ok_or(anyhow::anyhow!("No latest block"))?;
+                let base_fee = latest
+                    .header
+                    .base_fee_per_gas
+                    .ok_or(anyhow::anyhow!("No base fee in latest block"))?;
+                let priority_fee = match tip {
+                    Some(tip) => tip,
+                    None => {
+                        timed(
+                            "get_max_priority_fee_per_gas",
+                            provider.get_max_priority_fee_per_gas(),
+                        )
+                        .await?
+                    }
+                };
+                Ok(Some(Fees::Eip1559 {
+                    max_fee: base_fee as u128 * 2 + priority_fee,
+                    priority_fee,
+                }))
+            }
+        }
+    }
+
+    /// Fetch by the strategy, the auto strategy uses the provider estimation
+    pub async fn fetch_explicit(strategy: GasStrategy, provider: &impl Provider) -> Result<Fees> {
+        if let Some(fees) = Self::fetch(strategy, provider).await? {
+            return Ok(fees);
+        }
+        match timed("estimate_eip1559_fees", provider.estimate_eip1559_fees()).await {
+            Ok(estimation) => Ok(Fees::Eip1559 {
+                max_fee: estimation.max_fee_per_gas,
+                priority_fee: estimation.max_priority_fee_per_gas,
+            }),
+            Err(_) => {
+                let gas_price = timed("get_gas_price", provider.get_gas_price()).await?;
+                Ok(Fees::Legacy(gas_price))
+            }
+        }
+    }
+
+    // the higher of the fresh price and the bumped current price
+    fn bump(&self, fresh: Fees) -> Fees {
+        let bump = |v: u128| v * GAS_BUMP_PERCENT / 100;
+        match (*self, fresh) {
+            (
+                Fees::Eip1559 {
+                    max_fee,
+                    priority_fee,
+                },
+                Fees::Eip1559 {
+                    max_fee: fresh_max,
+                    priority_fee: fresh_priority,
+                },
+            ) => Fees::Eip1559 {
+                max_fee: bump(max_fee).max(fresh_max),
+                priority_fee: bump(priority_fee).max(fresh_priority),
+            },
+            (current, fresh) => Fees::Legacy(bump(current.max()).max(fresh.max())),
+        }
+    }
+
+    /// The max paid price per gas, bound the transaction cost
+    pub fn max(&self) -> u128 {
+        match self {
+            Fees::Legacy(gas_price) => *gas_price,
+            Fees::Eip1559 { max_fee, .. } => *max_fee,
+        }
+    }
+
+    /// Set the price to the transaction
+    pub fn apply(&self, tx: &mut TransactionRequest) {
+        match self {
+            Fees::Legacy(gas_price) => tx.set_gas_price(*gas_price),
+            Fees::Eip1559 {
+                max_fee,
+                priority_fee,
+            } => {
+                tx.set_max_fee_per_gas(*max_fee);
+                tx.set_max_priority_fee_per_gas(*priority_fee);
+            }
+        }
+    }
+}
+
+/// Submit the transaction by a gasless relayer which sponsors the gas.
+///
+/// The relayer protocol:
+/// - `POST {url}` with `{"chainId": 8453, "to": "0x..", "data": "0x.."}`, returns `{"id": "task-id"}`
+/// - `GET {url}/{id}` returns `{"status": "pending|submitted|failed", "hash": "0x.."}`
+pub struct RelayerSubmitter {
+    url: String,
+    chain_id: u64,
+    rpc: Url,
+    http: Client,
+}
+
+#[derive(Deserialize)]
+struct RelayerTask {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RelayerStatus {
+    status: String,
+    hash: Option<B256>,
+}
+
+impl RelayerSubmitter {
+    pub fn new(url: &str, chain_id: u64, rpc: Url, http: Client) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            chain_id,
+            rpc,
+            http,
+        }
+    }
+}
+
+#[async_trait]
+impl Submitter for RelayerSubmitter {
+    async fn submit(&self, to: Address, data: Bytes) -> Result<B256> {
+        // 1. post the call to relayer
+        let body = json!({
+            "chainId": self.chain_id,
+            "to": to.to_checksum(None),
+            "data": data,
+        });
+        let response = self
+            .http
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&body)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Relayer status code: {}",
+                response.status()
+            ));
+        }
+        let task: RelayerTask = serde_json::from_slice(&response.bytes().await?)?;
+
+        // 2. poll the task until relayer broadcast it
+        let mut hash = None;
+        for _ in 0..RELAYER_POLL_TIMES {
+            tokio::time::sleep(Duration::from_secs(RELAYER_POLL_INTERVAL)).await;
+            let response = self
+                .http
+                .get(format!("{}/{}", self.url, task.id))
+                .send()
+                .await?;
+            let status: RelayerStatus = serde_json::from_slice(&response.bytes().await?)?;
+            if status.status == "failed" {
+                return Err(anyhow::anyhow!("Relayer task {} failed", task.id));
+            }
+            if status.hash.is_some() {
+                hash = status.hash;
+                break;
+            }
+        }
+        let hash = hash.ok_or(anyhow::anyhow!("Relayer task {} timeout", task.id))?;
+
+        // 3. wait the transaction confirmed
+        let provider = ProviderBuilder::new().connect_reqwest(self.http.clone(), self.rpc.clone());
+        let pending_tx = PendingTransactionBuilder::new(provider.root().clone(), hash);
+        let receipt = tokio::time::timeout(
+            Duration::from_secs(RECEIPT_TIMEOUT),
+            timed("get_receipt", pending_tx.get_receipt()),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Relayer {hash} is not confirmed in {RECEIPT_TIMEOUT}s"))??;
+        succeeded(receipt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fees_bump() {
+        // the provider filled price is unknown, the fresh one is bumped
+        let fresh = Fees::Eip1559 {
+            max_fee: 100,
+            priority_fee: 10,
+        };
+        assert_eq!(fresh.bump(fresh).max(), 112);
+
+        // at least 12% above the last one, or the higher fresh one
+        assert_eq!(Fees::Legacy(100).bump(Fees::Legacy(50)).max(), 112);
+        assert_eq!(Fees::Legacy(100).bump(Fees::Legacy(200)).max(), 200);
+    }
+
+    #[test]
+    fn test_is_underpriced() {
+        assert!(is_underpriced("replacement transaction underpriced"));
+        assert!(is_underpriced("Fee too low"));
+        assert!(!is_underpriced("nonce too low"));
+    }
+}